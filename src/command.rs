@@ -0,0 +1,573 @@
+use std::env;
+use std::io::{self, Write};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+
+use crate::event::Change;
+
+
+/// Command lines longer than this are too long for `cmd.exe`, and a single argument past
+/// 128 KiB is rejected on Linux; beyond it, prefer `paths_to_stdin`.
+pub(crate) const COMMAND_LINE_LIMIT: usize = 8191;
+
+/// The shell commands are run through.
+pub(crate) const SHELL: &str = "pwsh";
+
+#[doc = r"
+    How spawned commands are set up, beyond the command string itself.
+"]
+#[derive(Clone, Default)]
+pub(crate) struct SpawnOptions {
+    pub niceness: Option<i32>,
+    pub new_window: bool,
+    // Run the command's first word as the program, with no shell in between
+    pub exec: bool,
+    // Start the command in its own session with no stdio, and don't track it
+    pub detach: bool,
+}
+
+#[doc = r"
+    Wrap a command so it runs in a new terminal window: `cmd /C start` on Windows,
+    Terminal.app through `osascript` on macOS and `x-terminal-emulator -e` elsewhere.
+    The window owns the command's output, so nothing is shown in xpectate's console.
+"]
+fn in_new_window(inner: &Command) -> Command {
+    let program = inner.get_program().to_string_lossy().into_owned();
+    let args: Vec<String> = inner.get_args().map(|a| a.to_string_lossy().into_owned()).collect();
+
+    if cfg!(windows) {
+        let mut command = Command::new("cmd");
+        // The empty string is the window title `start` expects before the program
+        command.args(["/C", "start", ""]).arg(program).args(args);
+        command
+    } else if cfg!(target_os = "macos") {
+        let line: Vec<String> = std::iter::once(program)
+            .chain(args)
+            .map(|a| format!("'{}'", a.replace('\'', "'\\''")))
+            .collect();
+        let script = line.join(" ").replace('\\', "\\\\").replace('"', "\\\"");
+        let mut command = Command::new("osascript");
+        command.arg("-e").arg(format!("tell application \"Terminal\" to do script \"{}\"", script));
+        command
+    } else {
+        let mut command = Command::new("x-terminal-emulator");
+        command.arg("-e").arg(program).args(args);
+        command
+    }
+}
+
+#[doc = r"
+    Set up the process attributes `spawn` asks for before the command starts.
+
+    Niceness lowers (or raises) the scheduling priority. On Unix it is clamped to
+    -20..=19 and applied with `setpriority`; raising priority (a negative value) needs
+    the matching privileges and is silently skipped otherwise. On Windows it maps to a
+    priority class: 10 and above is idle, 1 to 9 below normal, -1 to -9 above normal and
+    -10 and below high.
+
+    Detaching starts the command in a new session with `setsid` on Unix, so it has no
+    controlling terminal and is not signalled with xpectate's process group, and as a
+    `DETACHED_PROCESS` in a new process group on Windows.
+"]
+#[cfg(unix)]
+fn apply_process_attrs(command: &mut Command, spawn: &SpawnOptions) {
+    use std::os::unix::process::CommandExt;
+
+    let niceness = spawn.niceness.map(|niceness| niceness.clamp(-20, 19));
+    let detach = spawn.detach;
+    if niceness.is_none() && !detach {
+        return;
+    }
+    // SAFETY: `setsid` and `setpriority` are async-signal-safe and only affect the child being spawned
+    unsafe {
+        command.pre_exec(move || {
+            if detach {
+                libc::setsid();
+            }
+            if let Some(niceness) = niceness {
+                libc::setpriority(libc::PRIO_PROCESS as _, 0, niceness);
+            }
+            Ok(())
+        });
+    }
+}
+
+#[cfg(windows)]
+fn apply_process_attrs(command: &mut Command, spawn: &SpawnOptions) {
+    use std::os::windows::process::CommandExt;
+
+    const IDLE_PRIORITY_CLASS: u32 = 0x0000_0040;
+    const BELOW_NORMAL_PRIORITY_CLASS: u32 = 0x0000_4000;
+    const ABOVE_NORMAL_PRIORITY_CLASS: u32 = 0x0000_8000;
+    const HIGH_PRIORITY_CLASS: u32 = 0x0000_0080;
+    const DETACHED_PROCESS: u32 = 0x0000_0008;
+    const CREATE_NEW_PROCESS_GROUP: u32 = 0x0000_0200;
+
+    let class = match spawn.niceness.map(|niceness| niceness.clamp(-20, 19)) {
+        Some(10..) => IDLE_PRIORITY_CLASS,
+        Some(1..=9) => BELOW_NORMAL_PRIORITY_CLASS,
+        None | Some(0) => 0,
+        Some(-9..=-1) => ABOVE_NORMAL_PRIORITY_CLASS,
+        Some(_) => HIGH_PRIORITY_CLASS,
+    };
+    let detached = if spawn.detach { DETACHED_PROCESS | CREATE_NEW_PROCESS_GROUP } else { 0 };
+    // Creation flags replace each other, so they are set in one go
+    if class | detached != 0 {
+        command.creation_flags(class | detached);
+    }
+}
+
+#[doc = r"
+    Check whether a program can be found on `PATH`.
+
+    Arguments:
+        program (str): The program name, without any Windows executable extension.
+
+    Returns:
+        bool: Whether an executable with that name exists on `PATH`.
+"]
+pub(crate) fn is_on_path(program: &str) -> bool {
+    let Some(paths) = env::var_os("PATH") else { return false };
+    let names: Vec<String> = if cfg!(windows) {
+        vec![format!("{}.exe", program), format!("{}.cmd", program), program.to_string()]
+    } else {
+        vec![program.to_string()]
+    };
+
+    env::split_paths(&paths).any(|dir| names.iter().any(|name| dir.join(name).is_file()))
+}
+
+#[doc = r"
+    Split a `#!shell` prefix off a command string. The prefix names the program and is
+    followed by its flags (tokens starting with `-`, or also `/` on Windows, so an
+    absolute script path on Unix is not mistaken for one). With flags, everything after
+    them is a single script argument, e.g. `#!python -c print(1)` runs `python` with `-c`
+    and `print(1)`. Without, every word is an argument of its own, e.g.
+    `#!python script.py -v` runs `python` with `script.py` and `-v`.
+
+    Arguments:
+        arg_str (str): The command string.
+
+    Returns:
+        Optional[Tuple[List[str], List[str]]]: The shell program with its flags and the
+        arguments after them, or `None` when the command has no prefix.
+"]
+pub(crate) fn split_shell_prefix(arg_str: &str) -> Option<(Vec<String>, Vec<String>)> {
+    let mut rest = arg_str.strip_prefix("#!")?.trim_start();
+    let mut shell: Vec<String> = Vec::new();
+
+    loop {
+        let (token, tail) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        let is_flag = token.starts_with('-') || (cfg!(windows) && token.starts_with('/'));
+        if token.is_empty() || (!shell.is_empty() && !is_flag) {
+            break;
+        }
+        shell.push(token.to_string());
+        rest = tail.trim_start();
+    }
+
+    if shell.is_empty() {
+        return None;
+    }
+    let args = match shell.len() {
+        1 => rest.split_whitespace().map(|word| word.to_string()).collect(),
+        _ if rest.is_empty() => Vec::new(),
+        _ => vec![rest.to_string()],
+    };
+    Some((shell, args))
+}
+
+#[doc = r"
+    The program a command string will be run by: its `#!shell` prefix if it has one,
+    otherwise its first word in `exec` mode or the session shell.
+"]
+pub(crate) fn shell_program(arg_str: &str, exec: bool) -> String {
+    match split_shell_prefix(arg_str) {
+        Some((shell, _)) => shell[0].clone(),
+        None if exec => arg_str.split_whitespace().next().unwrap_or_default().to_string(),
+        None => SHELL.to_string(),
+    }
+}
+
+#[doc = r"
+    Encode a string as a JSON string literal. Single quotes are escaped as `\u0027` so
+    the result can always be wrapped in single quotes on the command line.
+"]
+fn json_string(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '\'' => out.push_str("\\u0027"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[doc = r"
+    Serialize a batch of changes as a JSON list of `{kind, path, timestamp}` objects.
+"]
+pub(crate) fn events_json(changes: &[Change]) -> String {
+    let items: Vec<String> = changes
+        .iter()
+        .map(|c| {
+            format!(
+                "{{\"kind\":{},\"path\":{},\"timestamp\":{}}}",
+                json_string(&c.kind),
+                json_string(&c.path),
+                c.timestamp
+            )
+        })
+        .collect();
+    format!("[{}]", items.join(","))
+}
+
+#[doc = r"
+    Substitute the placeholders in a command for the batch of changes it runs for:
+
+        {events_json}: the batch as a JSON list of `{kind, path, timestamp}` objects,
+        containing no single quotes, so `--changes '{events_json}'` is safe in POSIX
+        shells and PowerShell alike.
+        {path}: the path of the most recent change, as reported.
+        {out:EXT}: that path with its extension replaced by `EXT`, e.g. `{out:css}` turns
+        `a.scss` into `a.css`. Only the last extension is replaced (`a.tar.gz` becomes
+        `a.tar.css`), a path without one gets `EXT` appended (`Makefile` becomes
+        `Makefile.css`) and `{out:}` strips the extension.
+
+    Placeholders are substituted in a single pass, so text they insert is never expanded
+    again, and any other braces are left alone. Paths are inserted verbatim, without
+    quoting.
+
+    Arguments:
+        arg_str (str): The command as configured.
+        changes (List[Change]): The changes the command runs for.
+
+    Returns:
+        str: The command to run.
+"]
+pub(crate) fn expand_placeholders(arg_str: &str, changes: &[Change]) -> String {
+    let command = substitute_placeholders(arg_str, changes);
+    warn_if_long(command.len());
+    command
+}
+
+#[doc = r"
+    Split a command on whitespace into the program and arguments `exec` mode runs, then
+    expand the placeholders within each word, so a substituted path containing spaces
+    stays a single argument instead of turning into several.
+
+    Returns:
+        List[str]: The program followed by its arguments.
+"]
+pub(crate) fn exec_words(arg_str: &str, changes: &[Change]) -> Vec<String> {
+    let words: Vec<String> = arg_str.split_whitespace().map(|word| substitute_placeholders(word, changes)).collect();
+    warn_if_long(words.iter().map(|word| word.len() + 1).sum());
+    words
+}
+
+fn warn_if_long(len: usize) {
+    if len > COMMAND_LINE_LIMIT {
+        eprintln!(
+            "Warning: command is {} characters long, which some platforms reject; consider paths_to_stdin",
+            len
+        );
+    }
+}
+
+#[doc = r"
+    `expand_placeholders` without the warning about long commands, for measuring one.
+"]
+pub(crate) fn substitute_placeholders(arg_str: &str, changes: &[Change]) -> String {
+    let path = changes.last().map(|change| change.path.as_str()).unwrap_or_default();
+    let mut command = String::with_capacity(arg_str.len());
+    let mut rest = arg_str;
+    while let Some(start) = rest.find('{') {
+        command.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let Some(end) = rest.find('}') else { break };
+        let name = &rest[1..end];
+        match name.strip_prefix("out:") {
+            _ if name == "events_json" => command.push_str(&events_json(changes)),
+            _ if name == "path" => command.push_str(path),
+            Some(ext) => {
+                let out = Path::new(path).with_extension(ext.trim_start_matches('.'));
+                command.push_str(&out.to_string_lossy());
+            }
+            None => {
+                // Not a placeholder, keep the brace and look again after it
+                command.push('{');
+                rest = &rest[1..];
+                continue;
+            }
+        }
+        rest = &rest[end + 1..];
+    }
+    command.push_str(rest);
+    command
+}
+
+#[doc = r"
+    Split a batch of changes into runs whose paths, appended as arguments to a command
+    of `base_len` characters, keep each command line within `COMMAND_LINE_LIMIT`. Every
+    run holds at least one change, even one whose path alone is over the limit.
+"]
+pub(crate) fn arg_batches(base_len: usize, changes: &[Change]) -> Vec<&[Change]> {
+    let mut batches = Vec::new();
+    let mut start = 0;
+    let mut len = base_len;
+    for (i, change) in changes.iter().enumerate() {
+        // Each path costs its length plus the separating space
+        let cost = change.path.len() + 1;
+        if i > start && len + cost > COMMAND_LINE_LIMIT {
+            batches.push(&changes[start..i]);
+            start = i;
+            len = base_len;
+        }
+        len += cost;
+    }
+    if start < changes.len() {
+        batches.push(&changes[start..]);
+    }
+    batches
+}
+
+#[doc = r"
+    The environment variables describing the changes behind a run:
+
+    - `XPECTATE_PATH`: the path of the most recent change.
+    - `XPECTATE_KIND`: the kind of the most recent change (e.g. `Modify`).
+    - `XPECTATE_PATHS`: the paths of every change in the batch, one per line, in order.
+
+    They are set on every spawn, so each run sees only its own batch.
+"]
+pub(crate) fn event_env(changes: &[Change]) -> [(&'static str, String); 3] {
+    let (kind, path) = match changes.last() {
+        Some(change) => (change.kind.clone(), change.path.clone()),
+        None => Default::default(),
+    };
+    let paths: Vec<&str> = changes.iter().map(|c| c.path.as_str()).collect();
+    [
+        ("XPECTATE_PATH", path),
+        ("XPECTATE_KIND", kind),
+        ("XPECTATE_PATHS", paths.join("\n")),
+    ]
+}
+
+#[doc = r"
+    Call a command in the shell. A command starting with a `#!shell` prefix (see
+    `split_shell_prefix`) is run by that shell instead of the session shell, with the
+    rest of the command as a single script argument after its flags, or as separate
+    arguments when it has none. In `exec` mode any other command runs
+    its first word as the program, with the remaining words as its arguments.
+    Placeholders are expanded for `changes`, in `exec` mode within each word (see
+    `exec_words`).
+
+    Arguments:
+        arg_str (str): The command to call, as configured.
+        extra_args (List[str]): Arguments appended after the command's own. They are
+        passed as is in `exec` mode and appended to the command text, unquoted, when a
+        shell runs it.
+        input (Optional[str]): Text to write to the command's stdin before closing it.
+        When `None` the command inherits xpectate's stdin.
+        changes (list[Change]): The changes that triggered the run, substituted for the
+        placeholders and exported through `event_env`.
+        spawn (SpawnOptions): How the process is set up.
+
+    Returns:
+        Child: The child process.
+
+    Raises:
+        io::Error: If the program (or the terminal for `new_window`) cannot be started.
+"]
+pub(crate) fn call_command(
+    arg_str: String,
+    extra_args: &[&str],
+    input: Option<String>,
+    changes: &[Change],
+    spawn: &SpawnOptions,
+) -> io::Result<Child> {
+    let mut echo = match split_shell_prefix(&arg_str) {
+        Some((shell, args)) => {
+            let mut args: Vec<String> = args.iter().map(|arg| substitute_placeholders(arg, changes)).collect();
+            warn_if_long(args.iter().map(|arg| arg.len() + 1).sum());
+            let mut echo = Command::new(&shell[0]);
+            echo.args(&shell[1..]);
+            if shell.len() > 1 {
+                // The script is a single argument, so the extra ones become part of its text
+                let script = args.iter().map(String::as_str).chain(extra_args.iter().copied());
+                let script = script.filter(|s| !s.is_empty()).collect::<Vec<_>>().join(" ");
+                args = if script.is_empty() { Vec::new() } else { vec![script] };
+            } else {
+                args.extend(extra_args.iter().map(|arg| arg.to_string()));
+            }
+            echo.args(args);
+            echo
+        }
+        None if spawn.exec => {
+            let words = exec_words(&arg_str, changes);
+            let mut echo = Command::new(words.first().map(String::as_str).unwrap_or_default());
+            echo.args(words.get(1..).unwrap_or_default()).args(extra_args);
+            echo
+        }
+        None => {
+            let args: Vec<String> =
+                expand_placeholders(&arg_str, changes).split_whitespace().map(|s| s.to_string()).collect();
+
+            // println!("Running command: {:?}", args);
+            let mut echo = Command::new(SHELL);
+            echo.arg("-Command");
+            echo.args(&args).args(extra_args);
+            echo
+        }
+    };
+    if spawn.new_window {
+        echo = in_new_window(&echo);
+    }
+    echo.envs(event_env(changes));
+    if spawn.detach {
+        echo.stdin(Stdio::null()).stdout(Stdio::null()).stderr(Stdio::null());
+    }
+    if input.is_some() {
+        echo.stdin(Stdio::piped());
+    }
+    apply_process_attrs(&mut echo, spawn);
+    let mut child = echo.spawn()?;
+
+    if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+        // Write from a separate thread so a command that reads slowly (or never) can't block the watch
+        thread::spawn(move || {
+            let _ = stdin.write_all(input.as_bytes());
+        });
+    }
+    Ok(child)
+}
+
+#[doc = r"
+    Let go of a detached command: it is waited on from a background thread only so that
+    it does not linger as a zombie once it exits, and is never stopped or tracked.
+"]
+pub(crate) fn release(mut child: Child) {
+    thread::spawn(move || {
+        let _ = child.wait();
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn changes(paths: &[&str]) -> Vec<Change> {
+        paths.iter().map(|path| Change::new("Modify".to_string(), path.to_string())).collect()
+    }
+
+    fn batch_sizes(base_len: usize, changes: &[Change]) -> Vec<usize> {
+        arg_batches(base_len, changes).iter().map(|batch| batch.len()).collect()
+    }
+
+    #[test]
+    fn arg_batches_of_nothing_is_empty() {
+        assert!(arg_batches(10, &[]).is_empty());
+    }
+
+    #[test]
+    fn arg_batches_split_at_the_limit() {
+        // With the separating space each path costs 100, so 81 fit after a 91 character command
+        let path = "p".repeat(99);
+        let batch = changes(&vec![path.as_str(); 82]);
+        assert_eq!(batch_sizes(91, &batch), [81, 1]);
+        assert_eq!(batch_sizes(92, &batch), [80, 2]);
+        assert_eq!(batch_sizes(91, &batch[..81]), [81]);
+    }
+
+    #[test]
+    fn arg_batches_keep_an_oversized_path_on_its_own() {
+        let long = "p".repeat(COMMAND_LINE_LIMIT);
+        assert_eq!(batch_sizes(10, &changes(&[&long])), [1]);
+        assert_eq!(batch_sizes(10, &changes(&["a", &long, "b"])), [1, 1, 1]);
+    }
+
+    #[test]
+    fn out_placeholder_replaces_the_last_extension() {
+        let expand = |path: &str, command: &str| expand_placeholders(command, &changes(&[path]));
+        assert_eq!(expand("src/a.scss", "sass {path} {out:css}"), "sass src/a.scss src/a.css");
+        assert_eq!(expand("a.tar.gz", "{out:.css}"), "a.tar.css");
+        assert_eq!(expand("Makefile", "{out:bak}"), "Makefile.bak");
+        assert_eq!(expand("a.scss", "{out:}"), "a");
+        assert_eq!(expand("Makefile", "{out:}"), "Makefile");
+        // A name that is only a dot, or starts with one, has no extension to replace
+        assert_eq!(expand(".", "{out:css}"), ".");
+        assert_eq!(expand(".bashrc", "{out:bak}"), ".bashrc.bak");
+    }
+
+    #[test]
+    fn exec_words_keep_a_substituted_path_whole() {
+        let batch = changes(&["dir/a --delete b.txt"]);
+        assert_eq!(exec_words("rm -f {path}", &batch), ["rm", "-f", "dir/a --delete b.txt"]);
+        assert_eq!(exec_words("sass {path}:{out:css}", &batch), ["sass", "dir/a --delete b.txt:dir/a --delete b.css"]);
+        assert!(exec_words("", &batch).is_empty());
+    }
+
+    #[test]
+    fn placeholders_for_no_changes_are_empty() {
+        assert_eq!(expand_placeholders("run {path}|{out:css}|{events_json}", &[]), "run ||[]");
+    }
+
+    #[test]
+    fn unknown_placeholders_are_kept() {
+        let batch = changes(&["a.rs"]);
+        assert_eq!(expand_placeholders("awk '{print}' {path} {", &batch), "awk '{print}' a.rs {");
+        // Inserted text is not expanded again
+        assert_eq!(expand_placeholders("{path}", &changes(&["{out:css}"])), "{out:css}");
+    }
+
+    #[test]
+    fn shell_prefix_needs_a_program() {
+        assert_eq!(split_shell_prefix("#!"), None);
+        assert_eq!(split_shell_prefix("#!   "), None);
+        assert_eq!(split_shell_prefix("bash -c true"), None);
+    }
+
+    #[test]
+    fn shell_prefix_splits_program_flags_and_script() {
+        let split = |command: &str| split_shell_prefix(command).unwrap();
+        let strings = |words: &[&str]| words.iter().map(|w| w.to_string()).collect::<Vec<_>>();
+        assert_eq!(split("#!bash"), (strings(&["bash"]), vec![]));
+        assert_eq!(split("#!bash -c"), (strings(&["bash", "-c"]), vec![]));
+        assert_eq!(
+            split("#!  bash   -e  -c   echo  hi"),
+            (strings(&["bash", "-e", "-c"]), strings(&["echo  hi"]))
+        );
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn shell_prefix_takes_slash_flags_on_windows() {
+        let split = |command: &str| split_shell_prefix(command).unwrap();
+        let strings = |words: &[&str]| words.iter().map(|w| w.to_string()).collect::<Vec<_>>();
+        assert_eq!(split("#!cmd /C dir"), (strings(&["cmd", "/C"]), strings(&["dir"])));
+    }
+
+    #[test]
+    fn shell_prefix_without_flags_passes_separate_arguments() {
+        let split = |command: &str| split_shell_prefix(command).unwrap();
+        let strings = |words: &[&str]| words.iter().map(|w| w.to_string()).collect::<Vec<_>>();
+        assert_eq!(split("#!python script.py -v"), (strings(&["python"]), strings(&["script.py", "-v"])));
+        assert_eq!(split("#!bash  build.sh"), (strings(&["bash"]), strings(&["build.sh"])));
+        if cfg!(not(windows)) {
+            // An absolute script path is an argument, not a flag
+            assert_eq!(
+                split("#!python /abs/build.py a b"),
+                (strings(&["python"]), strings(&["/abs/build.py", "a", "b"]))
+            );
+        }
+    }
+}
@@ -0,0 +1,400 @@
+use notify::event::{
+    AccessKind, AccessMode, CreateKind, DataChange, Flag, MetadataKind, ModifyKind, RemoveKind, RenameMode,
+};
+use notify::{Event, EventKind};
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::paths::display_path;
+
+
+#[doc = r"
+    A change waiting to be handed to the command.
+"]
+#[derive(Clone, Debug)]
+pub(crate) struct Change {
+    pub kind: String,
+    pub path: String,
+    // Seconds since the Unix epoch
+    pub timestamp: f64,
+}
+
+impl Change {
+    pub fn new(kind: String, path: String) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs_f64())
+            .unwrap_or_default();
+        Change { kind, path, timestamp }
+    }
+}
+
+#[doc = r"
+    The shape callbacks receive each change in, chosen with `event_format`.
+"]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) enum EventFormat {
+    // `(kind, path)`, or `(kind, path, attrs)` with `include_attrs`
+    #[default]
+    Tuple,
+    // `{'kind': ..., 'paths': [...], 'timestamp': ...}`
+    Dict,
+    // An `Event` instance
+    Object,
+}
+
+impl EventFormat {
+    pub(crate) fn parse(name: &str) -> Option<Self> {
+        match name {
+            "tuple" => Some(EventFormat::Tuple),
+            "dict" => Some(EventFormat::Dict),
+            "object" => Some(EventFormat::Object),
+            _ => None,
+        }
+    }
+}
+
+#[pyclass(name = "Event", module = "xpectate", frozen, get_all)]
+#[doc = r"
+    A change passed to callbacks when `event_format='object'`.
+
+    Attributes:
+        kind (str): The event kind: `Access`, `Create`, `Modify`, `Remove` or `Other`.
+        paths (list[str]): The paths the event is about; a rename may report two.
+        timestamp (float): When the change was processed, in seconds since the Unix epoch.
+        attrs (Optional[dict]): The event's extra attributes with `include_attrs`, else `None`.
+"]
+pub struct ChangeEvent {
+    kind: String,
+    paths: Vec<String>,
+    timestamp: f64,
+    attrs: Option<PyObject>,
+}
+
+#[pymethods]
+impl ChangeEvent {
+    fn __repr__(&self, py: Python) -> PyResult<String> {
+        let paths = self.paths.to_object(py);
+        Ok(format!(
+            "Event(kind='{}', paths={}, timestamp={})",
+            self.kind,
+            paths.as_ref(py).repr()?,
+            self.timestamp
+        ))
+    }
+}
+
+#[doc = r"
+    Build the payload a callback receives for an event in the requested format.
+
+    Arguments:
+        format (EventFormat): The shape to build.
+        paths (list[str]): Every path of the event, as reported.
+        change (Change): Its kind, first path and timestamp.
+        attrs (Optional[dict]): Extra attributes to include, from `event_attrs`.
+
+    Returns:
+        object: The tuple, dict or `Event` to pass.
+"]
+pub(crate) fn event_payload(
+    py: Python,
+    format: EventFormat,
+    paths: Vec<String>,
+    change: &Change,
+    attrs: Option<&PyDict>,
+) -> PyResult<PyObject> {
+    Ok(match format {
+        EventFormat::Tuple => match attrs {
+            Some(attrs) => (change.kind.as_str(), change.path.as_str(), attrs).into_py(py),
+            None => (change.kind.as_str(), change.path.as_str()).into_py(py),
+        },
+        EventFormat::Dict => {
+            let dict = PyDict::new(py);
+            dict.set_item("kind", &change.kind)?;
+            dict.set_item("paths", paths)?;
+            dict.set_item("timestamp", change.timestamp)?;
+            if let Some(attrs) = attrs {
+                dict.set_item("attrs", attrs)?;
+            }
+            dict.into_py(py)
+        }
+        EventFormat::Object => ChangeEvent {
+            kind: change.kind.clone(),
+            paths,
+            timestamp: change.timestamp,
+            attrs: attrs.map(|attrs| attrs.into_py(py)),
+        }
+        .into_py(py),
+    })
+}
+
+#[doc = r"
+    Convert an `Event` to a tuple containing the event kind and the path that changed.
+
+    Arguments:
+        event (Event): The event to convert.
+
+    Returns:
+        Tuple[str, str]: A tuple containing the event kind and the path that changed, or an
+        empty path for an event that carries none (some `Other` and overflow events).
+"]
+pub(crate) fn event_to_tuple(event: &Event) -> (String, String) {
+    // We get a string representation of the event kind
+    let kind = match &event.kind {
+        EventKind::Access(_) => "Access",
+        EventKind::Create(_) => "Create",
+        EventKind::Modify(_) => "Modify",
+        EventKind::Remove(_) => "Remove",
+        EventKind::Other => "Other",
+        _ => "Unknown"
+    }.to_string();
+
+    // Get the first path for the event, if any
+    let path = event.paths.first().map(|p| display_path(p)).unwrap_or_default();
+
+    (kind, path)
+}
+
+#[doc = r"
+    Build a dict of the extra attributes notify attached to an event. Keys are always
+    present and `None` when the backend did not provide them:
+
+        detail: the full event kind, e.g. `Modify(Name(From))`.
+        tracker: an id shared by related events; inotify uses it to pair the two halves
+        of a rename (Linux only).
+        flag: `rescan` when events were dropped and the tree should be rescanned (inotify
+        queue overflow, FSEvents drops).
+        info: a free-form note, set by FSEvents (macOS) for rescans, mounts and a changed
+        root.
+        source: the event source, not set by the backends xpectate uses.
+        process_id: the process responsible, not currently set by any backend.
+
+    Arguments:
+        event (Event): The event to describe.
+
+    Returns:
+        dict: The event's attributes.
+"]
+pub(crate) fn event_attrs<'py>(py: Python<'py>, event: &Event) -> PyResult<&'py PyDict> {
+    let attrs = PyDict::new(py);
+    attrs.set_item("detail", format!("{:?}", event.kind))?;
+    attrs.set_item("tracker", event.attrs.tracker())?;
+    attrs.set_item("flag", event.attrs.flag().map(|flag| match flag {
+        Flag::Rescan => "rescan",
+    }))?;
+    attrs.set_item("info", event.attrs.info())?;
+    attrs.set_item("source", event.attrs.source())?;
+    attrs.set_item("process_id", event.attrs.process_id())?;
+    Ok(attrs)
+}
+
+#[doc = r"
+    The kinds an `event_kinds` selector can name below the given levels, spelled as the
+    notify variants they match. The top-level kinds come first when `parent` is empty.
+"]
+fn kind_names(parent: &[&str]) -> &'static [&'static str] {
+    match parent {
+        [] => &["Access", "Create", "Modify", "Remove", "Other", "Any"],
+        ["Access"] => &["Any", "Read", "Open", "Close", "Other"],
+        ["Access", "Open" | "Close"] => &["Any", "Execute", "Read", "Write", "Other"],
+        ["Create" | "Remove"] => &["Any", "File", "Folder", "Other"],
+        ["Modify"] => &["Any", "Data", "Metadata", "Name", "Other"],
+        ["Modify", "Data"] => &["Any", "Size", "Content", "Other"],
+        ["Modify", "Metadata"] => &["Any", "AccessTime", "WriteTime", "Permissions", "Ownership", "Extended", "Other"],
+        ["Modify", "Name"] => &["Any", "To", "From", "Both", "Other"],
+        _ => &[],
+    }
+}
+
+#[doc = r"
+    Break an event kind into its levels, e.g. `Modify(Metadata(Permissions))` becomes
+    `['Modify', 'Metadata', 'Permissions']`.
+"]
+pub(crate) fn kind_path(kind: &EventKind) -> Vec<String> {
+    // The nested Debug output of the kind enums spells out exactly these levels
+    format!("{:?}", kind)
+        .split('(')
+        .map(|level| level.trim_end_matches(')').to_string())
+        .collect()
+}
+
+#[doc = r"
+    Parse an `event_kinds` selector such as `Modify` or `Modify::Metadata`.
+
+    Returns:
+        Optional[List[str]]: The selector's levels spelled as the notify variants, or
+        `None` if any level is not a kind notify reports there.
+"]
+pub(crate) fn parse_kind_selector(selector: &str) -> Option<Vec<String>> {
+    let mut levels = Vec::new();
+    for level in selector.split("::").map(str::trim) {
+        let name = kind_names(&levels).iter().find(|name| name.eq_ignore_ascii_case(level))?;
+        levels.push(*name);
+    }
+    Some(levels.into_iter().map(str::to_string).collect())
+}
+
+#[doc = r"
+    Build the event kind a parsed selector names, e.g. `Modify(Data(Any))` for
+    `['Modify', 'Data']`. Levels the selector leaves out are `Any`.
+
+    Returns:
+        Optional[EventKind]: The kind, or `None` if the levels are not a valid selector.
+"]
+pub(crate) fn kind_from_selector(selector: &[String]) -> Option<EventKind> {
+    let levels: Vec<&str> = selector.iter().map(String::as_str).collect();
+    let access_mode = |rest: &[&str]| match rest {
+        [] | ["Any"] => Some(AccessMode::Any),
+        ["Execute"] => Some(AccessMode::Execute),
+        ["Read"] => Some(AccessMode::Read),
+        ["Write"] => Some(AccessMode::Write),
+        ["Other"] => Some(AccessMode::Other),
+        _ => None,
+    };
+    let create = |rest: &[&str]| match rest {
+        [] | ["Any"] => Some(CreateKind::Any),
+        ["File"] => Some(CreateKind::File),
+        ["Folder"] => Some(CreateKind::Folder),
+        ["Other"] => Some(CreateKind::Other),
+        _ => None,
+    };
+    let remove = |rest: &[&str]| match rest {
+        [] | ["Any"] => Some(RemoveKind::Any),
+        ["File"] => Some(RemoveKind::File),
+        ["Folder"] => Some(RemoveKind::Folder),
+        ["Other"] => Some(RemoveKind::Other),
+        _ => None,
+    };
+    let data = |rest: &[&str]| match rest {
+        [] | ["Any"] => Some(DataChange::Any),
+        ["Size"] => Some(DataChange::Size),
+        ["Content"] => Some(DataChange::Content),
+        ["Other"] => Some(DataChange::Other),
+        _ => None,
+    };
+    let metadata = |rest: &[&str]| match rest {
+        [] | ["Any"] => Some(MetadataKind::Any),
+        ["AccessTime"] => Some(MetadataKind::AccessTime),
+        ["WriteTime"] => Some(MetadataKind::WriteTime),
+        ["Permissions"] => Some(MetadataKind::Permissions),
+        ["Ownership"] => Some(MetadataKind::Ownership),
+        ["Extended"] => Some(MetadataKind::Extended),
+        ["Other"] => Some(MetadataKind::Other),
+        _ => None,
+    };
+    let rename = |rest: &[&str]| match rest {
+        [] | ["Any"] => Some(RenameMode::Any),
+        ["To"] => Some(RenameMode::To),
+        ["From"] => Some(RenameMode::From),
+        ["Both"] => Some(RenameMode::Both),
+        ["Other"] => Some(RenameMode::Other),
+        _ => None,
+    };
+
+    Some(match levels.as_slice() {
+        ["Any"] => EventKind::Any,
+        ["Other"] => EventKind::Other,
+        ["Access", rest @ ..] => EventKind::Access(match rest {
+            [] | ["Any"] => AccessKind::Any,
+            ["Read"] => AccessKind::Read,
+            ["Open", mode @ ..] => AccessKind::Open(access_mode(mode)?),
+            ["Close", mode @ ..] => AccessKind::Close(access_mode(mode)?),
+            ["Other"] => AccessKind::Other,
+            _ => return None,
+        }),
+        ["Create", rest @ ..] => EventKind::Create(create(rest)?),
+        ["Remove", rest @ ..] => EventKind::Remove(remove(rest)?),
+        ["Modify", rest @ ..] => EventKind::Modify(match rest {
+            [] | ["Any"] => ModifyKind::Any,
+            ["Data", change @ ..] => ModifyKind::Data(data(change)?),
+            ["Metadata", kind @ ..] => ModifyKind::Metadata(metadata(kind)?),
+            ["Name", mode @ ..] => ModifyKind::Name(rename(mode)?),
+            ["Other"] => ModifyKind::Other,
+            _ => return None,
+        }),
+        _ => return None,
+    })
+}
+
+#[doc = r"
+    The error for an `event_kinds` selector that `parse_kind_selector` rejected.
+"]
+pub(crate) fn unknown_kind_error(selector: &str) -> PyErr {
+    PyValueError::new_err(format!(
+        "unknown event kind '{}', expected one of Access, Create, Modify, Remove, Other or Any, \
+         optionally narrowed with '::' (e.g. Modify::Data)",
+        selector
+    ))
+}
+
+#[doc = r"
+    Check an event kind against a parsed selector. Each level of the selector must match
+    the kind, except that a kind the backend reported as `Any` at some level matches any
+    more specific selector, since the backend could not tell.
+"]
+pub(crate) fn kind_matches(selector: &[String], kind: &EventKind) -> bool {
+    let path = kind_path(kind);
+    for (i, level) in selector.iter().enumerate() {
+        match path.get(i) {
+            Some(actual) if i > 0 && actual == "Any" => return true,
+            Some(actual) if actual.eq_ignore_ascii_case(level) => {}
+            _ => return false,
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kind_selector_levels_are_checked() {
+        assert_eq!(parse_kind_selector("modify::metadata"), Some(vec!["Modify".to_string(), "Metadata".to_string()]));
+        assert_eq!(parse_kind_selector("Access::Open::Execute").map(|levels| levels.len()), Some(3));
+        assert_eq!(parse_kind_selector("Modify::Metdata"), None);
+        assert_eq!(parse_kind_selector("Create::Data"), None);
+        assert_eq!(parse_kind_selector("Other::File"), None);
+        assert_eq!(parse_kind_selector("Modify::Data::Content::Any"), None);
+    }
+
+    #[test]
+    fn any_selector_matches_only_unclassified_kinds() {
+        let any = parse_kind_selector("Any").unwrap();
+        assert!(kind_matches(&any, &EventKind::Any));
+        assert!(!kind_matches(&any, &EventKind::Modify(ModifyKind::Any)));
+    }
+
+    #[test]
+    fn selectors_build_the_kind_they_name() {
+        let kind = |selector: &str| kind_from_selector(&parse_kind_selector(selector).unwrap());
+        assert_eq!(kind("Modify"), Some(EventKind::Modify(ModifyKind::Any)));
+        assert_eq!(kind("Modify::Data"), Some(EventKind::Modify(ModifyKind::Data(DataChange::Any))));
+        assert_eq!(
+            kind("modify::metadata::permissions"),
+            Some(EventKind::Modify(ModifyKind::Metadata(MetadataKind::Permissions)))
+        );
+        assert_eq!(kind("Access::Open::Execute"), Some(EventKind::Access(AccessKind::Open(AccessMode::Execute))));
+        assert_eq!(kind("Create::Folder"), Some(EventKind::Create(CreateKind::Folder)));
+        assert_eq!(kind("Any"), Some(EventKind::Any));
+
+        // A kind built from a selector is matched by it, and not by a sibling
+        let data = parse_kind_selector("Modify::Data").unwrap();
+        let metadata = parse_kind_selector("Modify::Metadata").unwrap();
+        let built = kind_from_selector(&data).unwrap();
+        assert!(kind_matches(&data, &built));
+        assert!(!kind_matches(&metadata, &built));
+    }
+
+    #[test]
+    fn pathless_event_has_empty_path() {
+        let overflow = Event::new(EventKind::Other).set_flag(Flag::Rescan);
+        assert_eq!(event_to_tuple(&overflow), ("Other".to_string(), String::new()));
+
+        let modify = Event::new(EventKind::Modify(ModifyKind::Any));
+        assert_eq!(event_to_tuple(&modify), ("Modify".to_string(), String::new()));
+    }
+}
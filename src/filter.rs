@@ -0,0 +1,155 @@
+use notify::Event;
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::event::{kind_matches, parse_kind_selector, unknown_kind_error};
+use crate::watcher::normalize_extensions;
+
+
+#[doc = r"
+    A condition on an event, combined into trees by `all_of` and `any_of`.
+"]
+#[derive(Clone, Debug)]
+pub(crate) enum Predicate {
+    // The path has one of these extensions
+    Extension(Vec<String>),
+    // The path is this directory or below it
+    Under(PathBuf),
+    // The kind matches one of these `event_kinds` selectors
+    Kind(Vec<Vec<String>>),
+    AllOf(Vec<Predicate>),
+    AnyOf(Vec<Predicate>),
+}
+
+impl Predicate {
+    #[doc = r"
+        Evaluate the predicate for an event. Path predicates hold if any of the event's
+        paths, or the first one relative to its watched root, satisfies them.
+    "]
+    pub(crate) fn matches(&self, event: &Event, relative: Option<&Path>) -> bool {
+        match self {
+            Predicate::Extension(exts) => event.paths.iter().any(|p| {
+                p.extension()
+                    .is_some_and(|ext| exts.contains(&ext.to_string_lossy().into_owned()))
+            }),
+            Predicate::Under(prefix) => {
+                event.paths.iter().any(|p| p.starts_with(prefix)) || relative.is_some_and(|r| r.starts_with(prefix))
+            }
+            Predicate::Kind(selectors) => selectors.iter().any(|selector| kind_matches(selector, &event.kind)),
+            Predicate::AllOf(predicates) => predicates.iter().all(|p| p.matches(event, relative)),
+            Predicate::AnyOf(predicates) => predicates.iter().any(|p| p.matches(event, relative)),
+        }
+    }
+}
+
+impl fmt::Display for Predicate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let quoted = |items: Vec<String>| items.iter().map(|i| format!("'{}'", i)).collect::<Vec<_>>().join(", ");
+        let nested = |predicates: &[Predicate]| predicates.iter().map(|p| p.to_string()).collect::<Vec<_>>().join(", ");
+        match self {
+            Predicate::Extension(exts) => write!(f, "EventFilter.extension({})", quoted(exts.clone())),
+            Predicate::Under(prefix) => write!(f, "EventFilter.under('{}')", prefix.display()),
+            Predicate::Kind(selectors) => write!(f, "EventFilter.kind({})", quoted(selectors.iter().map(|s| s.join("::")).collect())),
+            Predicate::AllOf(predicates) => write!(f, "EventFilter.all_of({})", nested(predicates)),
+            Predicate::AnyOf(predicates) => write!(f, "EventFilter.any_of({})", nested(predicates)),
+        }
+    }
+}
+
+#[pyclass(module = "xpectate", frozen)]
+#[doc = r"
+    A structured filter for the `filter` option, for conditions the flat `extensions`
+    list cannot express. Build one from predicates and combine them, e.g. for Rust or
+    TOML files under `src`:
+
+        EventFilter.all_of(EventFilter.extension('rs', 'toml'), EventFilter.under('src'))
+
+    It is evaluated per event, after `event_kinds` and before `extensions`.
+"]
+pub struct EventFilter {
+    pub(crate) predicate: Predicate,
+}
+
+#[pymethods]
+impl EventFilter {
+    #[staticmethod]
+    #[pyo3(signature = (*extensions))]
+    #[doc = r"
+        extension(*extensions: str) -> EventFilter
+
+        Match paths with any of these extensions, normalized like `extensions`.
+
+        Raises:
+            ValueError: If no extension is given or one is empty.
+    "]
+    fn extension(extensions: Vec<String>) -> PyResult<Self> {
+        if extensions.is_empty() {
+            return Err(PyValueError::new_err("extension() needs at least one extension"));
+        }
+        Ok(EventFilter { predicate: Predicate::Extension(normalize_extensions(extensions)?) })
+    }
+
+    #[staticmethod]
+    #[doc = r"
+        under(prefix: str) -> EventFilter
+
+        Match paths inside the directory `prefix`, given either as a full path or relative
+        to the watched root, e.g. `'src'`. Whole components are compared, so `'src'`
+        does not match `srcs/a.rs`.
+    "]
+    fn under(prefix: PathBuf) -> Self {
+        EventFilter { predicate: Predicate::Under(prefix) }
+    }
+
+    #[staticmethod]
+    #[pyo3(signature = (*kinds))]
+    #[doc = r"
+        kind(*kinds: str) -> EventFilter
+
+        Match events of any of these kinds, written as for `event_kinds`, e.g. `'Create'`
+        or `'Modify::Data'`.
+
+        Raises:
+            ValueError: If no kind is given or one is unknown.
+    "]
+    fn kind(kinds: Vec<String>) -> PyResult<Self> {
+        if kinds.is_empty() {
+            return Err(PyValueError::new_err("kind() needs at least one kind"));
+        }
+        let selectors = kinds
+            .iter()
+            .map(|kind| {
+                parse_kind_selector(kind).ok_or_else(|| unknown_kind_error(kind))
+            })
+            .collect::<PyResult<_>>()?;
+        Ok(EventFilter { predicate: Predicate::Kind(selectors) })
+    }
+
+    #[staticmethod]
+    #[pyo3(signature = (*filters))]
+    #[doc = r"
+        all_of(*filters: EventFilter) -> EventFilter
+
+        Match events every one of `filters` matches. With no filters, matches everything.
+    "]
+    fn all_of(filters: Vec<PyRef<EventFilter>>) -> Self {
+        EventFilter { predicate: Predicate::AllOf(filters.iter().map(|f| f.predicate.clone()).collect()) }
+    }
+
+    #[staticmethod]
+    #[pyo3(signature = (*filters))]
+    #[doc = r"
+        any_of(*filters: EventFilter) -> EventFilter
+
+        Match events at least one of `filters` matches. With no filters, matches nothing.
+    "]
+    fn any_of(filters: Vec<PyRef<EventFilter>>) -> Self {
+        EventFilter { predicate: Predicate::AnyOf(filters.iter().map(|f| f.predicate.clone()).collect()) }
+    }
+
+    fn __repr__(&self) -> String {
+        self.predicate.to_string()
+    }
+}
@@ -1,133 +1,339 @@
+// The pyo3 macros expand to impls that newer compilers flag as non-local
+#![allow(non_local_definitions)]
+
+use pyo3::exceptions::PyValueError;
 use pyo3::prelude::*;
+use pyo3::types::PyDict;
 use pyo3::{wrap_pyfunction, PyResult};
-use notify::{Config, RecommendedWatcher, RecursiveMode, Watcher, EventKind, Event};
-use std::path::Path;
-use std::process::{Child, Command};
-use std::sync::mpsc::channel;
-use std::time::{Duration, Instant};
+use std::path::PathBuf;
+use std::time::Duration;
+
+mod command;
+mod event;
+mod filter;
+mod paths;
+mod queue;
+mod watcher;
+
+use event::ChangeEvent;
+use filter::EventFilter;
+use watcher::{run_many, OnChange, Options, State, Watcher};
 
 
+#[pyfunction]
+#[pyo3(signature = (path, extensions=None, command=None, **options))]
 #[doc = r"
-    Convert an `Event` to a tuple containing the event kind and the path that changed.
+    watch(path: str, extensions: Optional[list[str]], cb: Optional[Callable], **options) -> None
+
+    Monitor the specified `path` for changes, filtering by file extension if
+    `extensions` is provided. If `cb` is provided, it will be called with a
+    tuple containing the event kind and the path that changed. This should be a command
+    that would be run in the shell, e.g. `npx tailwind -i /path -o /path` or `python -m http.server`.
+    Commands run through `pwsh -Command` unless they start with a `#!shell` prefix naming
+    another interpreter and its flags, e.g. `#!bash -c make css` or `#!python -c print(1)`,
+    which get the rest of the command as one script argument. Flags start with `-`, or
+    also `/` on Windows (`#!cmd /C dir`). A prefix without flags passes each remaining
+    word as its own argument, e.g. `#!python /opt/tools/build.py -v`.
+    With `use_shell=False` they are executed directly, without any shell.
+    The placeholder `{events_json}` is replaced with the changes the command runs for,
+    as a JSON list of `{kind, path, timestamp}` objects free of single quotes, so it can
+    be passed as `--changes '{events_json}'`. Past about 8,000 characters the command
+    line is too long for `cmd.exe` (and one argument past 128 KiB for Linux), so large
+    batches are better sent with `paths_to_stdin`.
+    `{path}` is replaced with the path of the most recent change and `{out:EXT}` with
+    that path's extension swapped for `EXT` (`{out:css}` turns `a.scss` into `a.css`; a
+    path without an extension gets it appended), for compile-to-sibling-file commands.
+    Every command also gets the batch in its environment, with no quoting to worry about:
+    `XPECTATE_PATH` and `XPECTATE_KIND` hold the path and kind of the most recent change,
+    and `XPECTATE_PATHS` holds every changed path, one per line. They are overwritten on
+    each run.
 
     Arguments:
-        event (Event): The event to convert.
+        path (str): The path to monitor for changes. A glob pattern such as `packages/*/src`
+        is expanded once at startup and every match is watched; paths created later that
+        match the pattern are not picked up. Raises `ValueError` if the pattern matches nothing.
+        A path that exists is watched as is, so a folder named like `app/[id]` works.
+        extensions (Optional[List[str]]): A list of file extensions to filter by.
+        Only changes to files with these extensions will be reported. Whitespace and a
+        leading dot are ignored (`'.css'` is `'css'`); empty entries raise `ValueError`.
+        command (Optional[Callable]): A callback to call when a change is detected.
+        **options: The keyword options accepted by `Watcher`, e.g. `quiet`.
 
     Returns:
-        Tuple[str, str]: A tuple containing the event kind and the path that changed.
+        None
 "]
-fn event_to_tuple(event: &Event) -> (String, String) {
-    // We get a string representation of the event kind
-    let kind = match &event.kind {
-        EventKind::Access(_) => "Access",
-        EventKind::Create(_) => "Create",
-        EventKind::Modify(_) => "Modify",
-        EventKind::Remove(_) => "Remove",
-        EventKind::Other => "Other",
-        _ => "Unknown"
-    }.to_string();
-
-    // Get the first path for the event, if any
-    let path = event.paths.get(0).map(|p| p.display().to_string());
-
-    (kind, path.unwrap())
+fn watch(
+    py: Python,
+    path: &str,
+    extensions: Option<Vec<String>>,
+    command: Option<&str>,
+    options: Option<&PyDict>,
+) -> PyResult<()> {
+    Watcher::new(path, extensions, command, options)?.run(py)
 }
 
+
+/// The debounce `shared_debounce` uses when `debounce_ms` is not given.
+const SHARED_DEBOUNCE: Duration = Duration::from_millis(300);
+
+#[pyfunction]
+#[pyo3(signature = (mappings, extensions=None, command=None, **options))]
 #[doc = r"
-    Call a command in the shell.
+    watch_many(mappings: dict[str, str] | list[tuple[str, str]], extensions: Optional[list[str]], command: Optional[str], **options) -> None
+
+    Watch several paths at once, each with its own command, e.g.
+    `watch_many({'css': 'npx tailwindcss', 'src': 'cargo build'})`. Every mapping is an
+    independent watch with its own batching, throttle and debounce, and all of them share
+    the remaining arguments. Blocks until every watch ends; if one fails the others are
+    stopped and its error is raised. `ready` is set, and `on_lifecycle` gets
+    `watch-started` and `watch-stopped`, once for all of them together.
 
     Arguments:
-        arg_str (str): The command to call.
+        mappings (dict[str, str] | list[tuple[str, str]]): Path (or glob) to command pairs.
+        extensions (Optional[List[str]]): A list of file extensions to filter by, as for `watch`.
+        command (Optional[str]): The command to run with `shared_debounce`; not allowed otherwise.
+        **options: The keyword options accepted by `Watcher`, plus:
+        shared_debounce (bool): Feed every path into a single watch with one debounce, so a
+        burst of changes across several trees runs `command` once when it settles. The
+        debounce is `debounce_ms` if given, otherwise 300 milliseconds. The mappings' own
+        commands are not run; `command` receives the changed paths from all of them (via
+        `paths_to_stdin`, `{events_json}` or the callbacks).
 
     Returns:
-        Child: The child process.
+        None
 "]
-fn call_command(arg_str: String) -> Child {
-    let args: Vec<String> = arg_str.split_whitespace().map(|s| s.to_string()).collect();
+fn watch_many(
+    py: Python,
+    mappings: &PyAny,
+    extensions: Option<Vec<String>>,
+    command: Option<&str>,
+    options: Option<&PyDict>,
+) -> PyResult<()> {
+    let mappings: Vec<(String, String)> = match mappings.downcast::<PyDict>() {
+        Ok(dict) => dict.items().extract()?,
+        Err(_) => mappings.extract()?,
+    };
+
+    // `shared_debounce` only exists for `watch_many`, so take it out before parsing the rest
+    let options = options.map(|o| o.copy()).transpose()?;
+    let shared = match options.and_then(|o| o.get_item("shared_debounce")) {
+        Some(value) => value.extract()?,
+        None => false,
+    };
+    if let Some(options) = options {
+        options.del_item("shared_debounce").ok();
+    }
+
+    let base = Options::new("", extensions, command, options)?;
+    let state = State::for_options(&base);
 
-    // println!("Running command: {:?}", args);
-    let mut echo = Command::new("pwsh");
-    echo.arg("-Command");
-    echo.args(&args).spawn().unwrap()
+    if shared {
+        if command.is_none() {
+            return Err(PyValueError::new_err("shared_debounce requires a command"));
+        }
+        let mut shared = base;
+        shared.paths = mappings.into_iter().map(|(path, _)| path).collect();
+        shared.debounce.get_or_insert(SHARED_DEBOUNCE);
+        return py.allow_threads(|| run_many(&[shared], &state));
+    }
+
+    if command.is_some() {
+        return Err(PyValueError::new_err("command is only used with shared_debounce; give each path its command in mappings"));
+    }
+    let sessions: Vec<Options> = mappings
+        .into_iter()
+        .map(|(path, command)| {
+            let mut session = base.clone();
+            session.paths = vec![path];
+            session.command = Some(command);
+            session
+        })
+        .collect();
+    py.allow_threads(|| run_many(&sessions, &state))
 }
 
+
 #[pyfunction]
+#[pyo3(signature = (path, extensions=None, command=None, **options))]
 #[doc = r"
-    watch(path: str, extensions: Optional[list[str]], cb: Optional[Callable]) -> None
+    on_change(path: str, extensions: Optional[list[str]], command: Optional[str], **options) -> Callable
 
-    Monitor the specified `path` for changes, filtering by file extension if
-    `extensions` is provided. If `cb` is provided, it will be called with a
-    tuple containing the event kind and the path that changed. This should be a command
-    that would be run in the shell, e.g. `npx tailwind -i /path -o /path` or `python -m http.server`.
+    Build a decorator that registers the decorated function as the change callback.
+    The decorated name becomes a `Watcher`; nothing is watched until its `run()` is
+    called:
+
+        @xpectate.on_change('src', extensions=['css'])
+        def rebuild(event):
+            print(event)
+
+        rebuild.run()
 
     Arguments:
-        path (str): The path to monitor for changes.
-        extensions (Optional[List[str]]): A list of file extensions to filter by.
-        Only changes to files with these extensions will be reported.
-        command (Optional[Callable]): A callback to call when a change is detected.
+        Same as `watch`.
+
+    Returns:
+        Callable: A decorator turning a function into a `Watcher` that calls it with a
+        `(kind, path)` tuple for every change.
+"]
+fn on_change(
+    path: &str,
+    extensions: Option<Vec<String>>,
+    command: Option<&str>,
+    options: Option<&PyDict>,
+) -> PyResult<OnChange> {
+    Ok(OnChange::new(Options::new(path, extensions, command, options)?))
+}
+
 
+#[pyfunction]
+#[pyo3(signature = (path, extensions=None, command=None, **options))]
+#[doc = r"
+    validate(path: str, extensions: Optional[list[str]], command: Optional[str], **options) -> list[str]
+
+    Check a configuration without starting a watch. The arguments are parsed exactly as
+    `watch` parses them, then the resources the watch depends on are checked: the path
+    or glob pattern resolves to something that exists, the extensions contain no path
+    separators and, when a command is given, the shell is available.
+
+    Arguments:
+        Same as `watch`.
 
     Returns:
-        None
+        List[str]: A message for every problem found. An empty list means `watch` should
+        start cleanly with these arguments.
 "]
-fn watch(path: &str, extensions: Option<Vec<String>>, command: Option<&str>) -> PyResult<()> {
-    println!("Watching {:?} for changes...", path);
-    let (tx, rx) = channel();
-
-    let mut watcher = RecommendedWatcher::new(tx, Config::default()).unwrap();
-
-    watcher.watch(Path::new(path), RecursiveMode::Recursive).unwrap();
-
-    let mut has_changes = false;
-    // Initialize to 1 second ago
-    let mut last_call_time = Instant::now() - Duration::new(1, 0);
-
-    for res in rx {
-        match res {
-            Ok(event) => {
-                // If extensions are provided, filter out events that don't match
-                if let Some(exts) = &extensions {
-                    let should_process = event.paths.iter().any(|p| {
-                        p.extension()
-                            .map(|ext| exts.contains(&ext.to_string_lossy().into_owned()))
-                            .unwrap_or(false)
-                    });
-
-                    if !should_process {
-                        continue;
-                    }
-                }
-
-                // Convert the event to a tuple
-                let event = event_to_tuple(&event);
-
-                if !has_changes {
-                    has_changes = true;
-                    println!("Change detected!");
-                    println!("Change: {:?}", event);
-                }
-            }
-            Err(error) => println!("Error: {:?}", error),
-        }
-        // If a cmd is provided, call it and we have changes
-        if let Some(cmd) = command {
-            if has_changes {
-                let now = Instant::now();
-                if now.duration_since(last_call_time) >= Duration::new(1, 0) {
-                    println!("Running command: {:?}", cmd);
-                    call_command(cmd.to_string());
-                    // Update the last call time
-                    last_call_time = now;
-                }
-            }
-        }
+fn validate(
+    path: &str,
+    extensions: Option<Vec<String>>,
+    command: Option<&str>,
+    options: Option<&PyDict>,
+) -> Vec<String> {
+    match Options::new(path, extensions, command, options) {
+        Ok(options) => options.problems(),
+        Err(e) => vec![e.to_string()],
     }
-    Ok(())
+}
+
+
+#[pyfunction]
+#[pyo3(signature = (path, timeout_secs=None))]
+#[doc = r"
+    wait_for_file(path: str, timeout_secs: Optional[float]) -> bool
+
+    Block until the file at `path` exists, e.g. a build artifact another process writes.
+    Returns straight away if it already exists; otherwise its parent directory is watched
+    and the call returns as soon as the file is created or moved there. The GIL is
+    released while waiting.
+
+    Arguments:
+        path (str): The file to wait for. Its parent directory must exist.
+        timeout_secs (Optional[float]): Give up after this many seconds. Waits forever if `None`.
+
+    Returns:
+        bool: `True` once the file exists, `False` if the timeout passed first.
+
+    Raises:
+        ValueError: If the parent directory does not exist or the timeout is negative.
+        RuntimeError: If the parent directory cannot be watched, e.g. because it is not
+        readable or the OS limit on watches (`fs.inotify.max_user_watches`) was reached.
+"]
+fn wait_for_file(py: Python, path: PathBuf, timeout_secs: Option<f64>) -> PyResult<bool> {
+    let timeout = timeout_secs
+        .map(Duration::try_from_secs_f64)
+        .transpose()
+        .map_err(|_| PyValueError::new_err("timeout_secs must be a non-negative number"))?;
+    py.allow_threads(|| watcher::wait_for_file(&path, timeout))
+}
+
+#[pyfunction]
+#[pyo3(signature = (path, extensions=None, ignore=None, include=None, kinds=None, kind="Modify", filter=None))]
+#[doc = r"
+    matches(path: str, extensions: Optional[list[str]], ignore: Optional[list[str]], include: Optional[list[str]], kinds: Optional[list[str]], kind: str = 'Modify', filter: Optional[EventFilter] = None) -> bool
+
+    Tell whether a change to `path` would be reported with the given filters, without
+    watching anything, e.g. to preview which files an editor integration would react to.
+    The decision is made by the same code the event loop uses. Paths are matched as
+    given; since there is no watched root, globs are not tried against a root-relative
+    path, so pass relative paths to preview relative patterns.
+
+    Arguments:
+        path (str): The path to check.
+        extensions (Optional[List[str]]): As for `watch`.
+        ignore (Optional[List[str]]): As the `ignore` option of `Watcher`.
+        include (Optional[List[str]]): As the `include` option of `Watcher`.
+        kinds (Optional[List[str]]): As the `event_kinds` option of `Watcher`.
+        kind (str): The kind of change to check: `Access`, `Create`, `Modify`, `Remove`
+        or `Other`, optionally narrowed like `kinds`, e.g. `Modify::Data`, so
+        `kinds=['Modify::Metadata']` can be told apart from a content edit. Defaults to
+        `Modify`, which any narrower `kinds` selector matches.
+        filter (Optional[EventFilter]): As the `filter` option of `Watcher`.
+
+    Returns:
+        bool: Whether the change would pass every filter.
+
+    Raises:
+        ValueError: If `kind`, an extension or a pattern is invalid.
+"]
+fn matches(
+    path: &str,
+    extensions: Option<&PyAny>,
+    ignore: Option<&PyAny>,
+    include: Option<&PyAny>,
+    kinds: Option<&PyAny>,
+    kind: &str,
+    filter: Option<&PyAny>,
+) -> PyResult<bool> {
+    let options = [
+        ("extensions", extensions),
+        ("ignore", ignore),
+        ("include", include),
+        ("event_kinds", kinds),
+        ("filter", filter),
+    ];
+    let options: Vec<(&str, &PyAny)> = options
+        .into_iter()
+        .filter_map(|(key, value)| value.map(|value| (key, value)))
+        .collect();
+    watcher::would_match(path, kind, &options)
+}
+
+#[pyfunction]
+#[doc = r"
+    backend_info() -> dict
+
+    Describe the default file watching backend `watch` uses on this platform. A running
+    watch can end up on another one, e.g. polling after `fallback_to_poll_on_limit`;
+    `Watcher.backend` names the backend actually in use.
+
+    Returns:
+        dict: `backend`, the backend name (`inotify`, `fsevents`, `kqueue`, `windows` or
+        `poll`); `poll_interval_ms`, the interval between scans when polling, else `None`;
+        and `compare_contents`, whether the poller hashes file contents to detect changes.
+"]
+fn backend_info(py: Python<'_>) -> PyResult<&PyDict> {
+    let (name, config) = watcher::backend();
+    let polling = name == "poll";
+
+    let info = PyDict::new(py);
+    info.set_item("backend", name)?;
+    info.set_item("poll_interval_ms", config.poll_interval_v2().filter(|_| polling).map(|d| d.as_millis() as u64))?;
+    info.set_item("compare_contents", polling && config.compare_contents())?;
+    Ok(info)
 }
 
 
 #[pymodule]
 fn xpectate(_py: Python, m: &PyModule) -> PyResult<()> {
     m.add_function(wrap_pyfunction!(watch, m)?)?;
+    m.add_function(wrap_pyfunction!(watch_many, m)?)?;
+    m.add_function(wrap_pyfunction!(wait_for_file, m)?)?;
+    m.add_function(wrap_pyfunction!(matches, m)?)?;
+    m.add_function(wrap_pyfunction!(validate, m)?)?;
+    m.add_function(wrap_pyfunction!(backend_info, m)?)?;
+    m.add_function(wrap_pyfunction!(on_change, m)?)?;
+    m.add_class::<Watcher>()?;
+    m.add_class::<OnChange>()?;
+    m.add_class::<ChangeEvent>()?;
+    m.add_class::<EventFilter>()?;
     Ok(())
 }
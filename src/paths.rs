@@ -0,0 +1,334 @@
+#[cfg(not(target_os = "linux"))]
+use pyo3::exceptions::PyNotImplementedError;
+use pyo3::exceptions::PyValueError;
+use pyo3::PyResult;
+use glob::Pattern;
+use std::path::{Path, PathBuf};
+
+
+/// Paths at or beyond this length need the extended-length prefix on Windows.
+#[cfg(windows)]
+const MAX_PATH: usize = 260;
+
+#[doc = r"
+    Prefix a long absolute path with `\\?\` so Windows accepts it past `MAX_PATH`.
+    Paths that are short, relative or already prefixed are returned unchanged.
+    This is a no-op on other platforms.
+
+    Arguments:
+        path (Path): The path to prefix.
+
+    Returns:
+        PathBuf: The path to hand to the watcher.
+"]
+#[cfg(windows)]
+pub(crate) fn to_extended_path(path: &Path) -> PathBuf {
+    let raw = path.to_string_lossy();
+    if !path.is_absolute() || raw.len() < MAX_PATH || raw.starts_with(r"\\?\") {
+        return path.to_path_buf();
+    }
+
+    // The extended-length form disables path normalization, so only backslashes are allowed
+    let raw = raw.replace('/', "\\");
+    match raw.strip_prefix(r"\\") {
+        Some(share) => PathBuf::from(format!(r"\\?\UNC\{}", share)),
+        None => PathBuf::from(format!(r"\\?\{}", raw)),
+    }
+}
+
+#[cfg(not(windows))]
+pub(crate) fn to_extended_path(path: &Path) -> PathBuf {
+    path.to_path_buf()
+}
+
+#[doc = r"
+    Make a root absolute, the form the backends report event paths in. On macOS it is also
+    resolved through symlinks when it exists, since FSEvents reports resolved paths (e.g.
+    `/private/tmp` for `/tmp`).
+
+    Arguments:
+        path (Path): The root as resolved from the watched paths.
+
+    Returns:
+        PathBuf: The root to register and match event paths against.
+"]
+pub(crate) fn absolute_root(path: &Path) -> PathBuf {
+    if cfg!(target_os = "macos") {
+        if let Ok(resolved) = path.canonicalize() {
+            return resolved;
+        }
+    }
+    std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+#[doc = r"
+    Render a path for reporting, removing any `\\?\` prefix added by `to_extended_path`.
+
+    Arguments:
+        path (Path): The path to display.
+
+    Returns:
+        str: The path without the extended-length prefix.
+"]
+pub(crate) fn display_path(path: &Path) -> String {
+    let raw = path.display().to_string();
+    if cfg!(windows) {
+        if let Some(share) = raw.strip_prefix(r"\\?\UNC\") {
+            return format!(r"\\{}", share);
+        }
+        if let Some(local) = raw.strip_prefix(r"\\?\") {
+            return local.to_string();
+        }
+    }
+    raw
+}
+
+/// Whether paths that differ only in case name the same file, as on the default Windows
+/// and macOS filesystems.
+const CASE_INSENSITIVE: bool = cfg!(any(windows, target_os = "macos"));
+
+#[doc = r"
+    The key deduplication compares paths by, so representations of the same path that
+    differ only cosmetically are recognized as one: separators are unified to `/`,
+    trailing separators are removed and, on case-insensitive platforms, case is folded.
+    The key is only compared, never reported.
+
+    Arguments:
+        path (str): The path as reported.
+
+    Returns:
+        str: The normalized key.
+"]
+pub(crate) fn dedup_key(path: &str) -> String {
+    let unified = if cfg!(windows) { path.replace('\\', "/") } else { path.to_string() };
+    let trimmed = unified.trim_end_matches('/');
+    // Keep the root itself
+    let key = if trimmed.is_empty() && !unified.is_empty() { "/" } else { trimmed };
+    if CASE_INSENSITIVE {
+        key.to_lowercase()
+    } else {
+        key.to_string()
+    }
+}
+
+#[doc = r"
+    The path an already-open directory descriptor can be watched through. On Linux this
+    is its `/proc/self/fd` entry, which inotify follows to the directory itself, so the
+    directory does not need to be reachable by its own path.
+
+    Raises:
+        ValueError: If `fd` is not an open directory.
+"]
+#[cfg(target_os = "linux")]
+pub(crate) fn fd_root(fd: i32) -> PyResult<PathBuf> {
+    let path = PathBuf::from(format!("/proc/self/fd/{}", fd));
+    if fd < 0 || !path.is_dir() {
+        return Err(PyValueError::new_err(format!("dir_fd {} is not an open directory", fd)));
+    }
+    Ok(path)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn fd_root(_fd: i32) -> PyResult<PathBuf> {
+    Err(PyNotImplementedError::new_err("dir_fd is only supported on Linux"))
+}
+
+#[doc = r"
+    Resolve the `path` argument to the concrete roots to watch. A path that exists is
+    returned as is, even if its name contains glob metacharacters (e.g. `app/[id]`).
+    Otherwise a path containing `*`, `?` or `[` is expanded to every existing match, and
+    any other path is returned as is.
+
+    Arguments:
+        path (str): The literal path or glob pattern to resolve.
+
+    Returns:
+        List[PathBuf]: The roots to register a watch for.
+
+    Raises:
+        ValueError: If the pattern is invalid or matches nothing.
+"]
+pub(crate) fn expand_roots(path: &str) -> PyResult<Vec<PathBuf>> {
+    if !path.contains(['*', '?', '[']) || Path::new(path).exists() {
+        return Ok(vec![PathBuf::from(path)]);
+    }
+
+    let matches = glob::glob(path)
+        .map_err(|e| PyValueError::new_err(format!("invalid path pattern '{}': {}", path, e)))?;
+    let roots: Vec<PathBuf> = matches.filter_map(Result::ok).collect();
+
+    if roots.is_empty() {
+        return Err(PyValueError::new_err(format!("path pattern '{}' did not match anything", path)));
+    }
+    Ok(roots)
+}
+
+#[doc = r"
+    Recursively collect every file below `dir`. Unreadable entries are skipped.
+
+    Arguments:
+        dir (Path): The directory to walk.
+
+    Returns:
+        List[PathBuf]: The files found, in directory order.
+"]
+pub(crate) fn walk_files(dir: &Path) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+
+    while let Some(current) = pending.pop() {
+        let Ok(entries) = std::fs::read_dir(&current) else { continue };
+        for entry in entries.filter_map(Result::ok) {
+            let path = entry.path();
+            match entry.file_type() {
+                Ok(kind) if kind.is_dir() => pending.push(path),
+                Ok(_) => files.push(path),
+                Err(_) => {}
+            }
+        }
+    }
+    files
+}
+
+#[doc = r"
+    Compile a path or glob pattern for `Watcher.mute`. A literal path is made absolute
+    so it matches however the event reports it, as is a path that exists even though it
+    contains glob metacharacters (e.g. `app/[id]/page.tsx`); a glob is matched as written.
+
+    Raises:
+        ValueError: If the glob pattern is invalid.
+"]
+pub(crate) fn mute_pattern(path: &str) -> PyResult<Pattern> {
+    let pattern = if path.contains(['*', '?', '[']) && !Path::new(path).exists() {
+        path.to_string()
+    } else {
+        let absolute = std::path::absolute(path).unwrap_or_else(|_| PathBuf::from(path));
+        Pattern::escape(&absolute.to_string_lossy())
+    };
+    Pattern::new(&pattern).map_err(|e| PyValueError::new_err(format!("invalid pattern '{}': {}", path, e)))
+}
+
+#[doc = r"
+    Strip the longest watched root that `path` lies under.
+
+    Arguments:
+        path (Path): The path an event reported.
+        roots (List[Path]): The watched roots, as registered with the watcher.
+
+    Returns:
+        Optional[PathBuf]: The path relative to its root, or `None` if it is under none.
+"]
+pub(crate) fn relative_to_roots(path: &Path, roots: &[PathBuf]) -> Option<PathBuf> {
+    roots
+        .iter()
+        .filter_map(|root| path.strip_prefix(root).ok())
+        .min_by_key(|rest| rest.components().count())
+        .map(Path::to_path_buf)
+}
+
+#[doc = r"
+    Bring the mirror of `source` up to date: copy it if it is a file, create it if it is
+    a directory, and delete the mirrored copy if it no longer exists.
+
+    Arguments:
+        source (Path): The path that changed.
+        target (Path): Where it is mirrored to.
+
+    Returns:
+        io::Result[None]: Any error copying or deleting, except a mirror copy that was
+        already gone.
+"]
+pub(crate) fn sync_mirror(source: &Path, target: &Path) -> std::io::Result<()> {
+    if source.is_dir() {
+        return std::fs::create_dir_all(target);
+    }
+    if source.is_file() {
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        return std::fs::copy(source, target).map(|_| ());
+    }
+
+    let removed = if target.is_dir() {
+        std::fs::remove_dir_all(target)
+    } else {
+        std::fs::remove_file(target)
+    };
+    match removed {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dedup_key_ignores_trailing_separators() {
+        assert_eq!(dedup_key("/tmp/w/"), dedup_key("/tmp/w"));
+        assert_eq!(dedup_key("src//"), "src");
+        assert_eq!(dedup_key(""), "");
+    }
+
+    #[test]
+    fn dedup_key_keeps_the_root() {
+        assert_eq!(dedup_key("/"), "/");
+        assert_eq!(dedup_key("///"), "/");
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn dedup_key_unifies_separators() {
+        assert_eq!(dedup_key(r"C:\src\a.rs"), dedup_key("C:/src/a.rs"));
+        assert_eq!(dedup_key(r"C:\src\"), dedup_key("C:/src"));
+    }
+
+    #[test]
+    #[cfg(not(windows))]
+    fn dedup_key_keeps_backslashes_in_names() {
+        assert_ne!(dedup_key(r"src\a.rs"), dedup_key("src/a.rs"));
+    }
+
+    #[test]
+    fn existing_paths_with_glob_characters_are_literal() {
+        let root = std::env::temp_dir().join(format!("xpectate-brackets-{}", std::process::id()));
+        let route = root.join("app").join("[id]");
+        std::fs::create_dir_all(&route).unwrap();
+        let route_str = route.to_string_lossy();
+        assert_eq!(expand_roots(&route_str).unwrap(), std::slice::from_ref(&route));
+        // Still a pattern once it does not exist as written
+        let pattern = root.join("app").join("[i]d");
+        assert!(expand_roots(&pattern.to_string_lossy()).is_err());
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn muting_an_existing_path_with_glob_characters_is_literal() {
+        let root = std::env::temp_dir().join(format!("xpectate-mute-brackets-{}", std::process::id()));
+        let page = root.join("[id]").join("page.tsx");
+        std::fs::create_dir_all(page.parent().unwrap()).unwrap();
+        std::fs::write(&page, "").unwrap();
+        let pattern = mute_pattern(&page.to_string_lossy()).unwrap();
+        assert!(pattern.matches_path(&std::path::absolute(&page).unwrap()));
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn absolute_root_anchors_relative_paths() {
+        let root = absolute_root(Path::new("src"));
+        assert!(root.is_absolute());
+        assert!(root.ends_with("src"));
+    }
+
+    #[test]
+    fn dedup_key_folds_case_where_the_filesystem_does() {
+        assert_eq!(dedup_key("/src/Main.RS") == dedup_key("/src/main.rs"), CASE_INSENSITIVE);
+    }
+
+    #[test]
+    #[cfg(any(windows, target_os = "macos"))]
+    fn dedup_key_is_lowercase() {
+        assert_eq!(dedup_key("/Users/Me/A.txt"), "/users/me/a.txt");
+    }
+}
@@ -0,0 +1,254 @@
+use notify::{Event, EventHandler};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+// The default number of events held before the overflow policy applies
+pub(crate) const DEFAULT_CAPACITY: usize = 10_000;
+
+#[doc = r"
+    What the backend does with an event when the queue is full.
+"]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) enum Overflow {
+    // Wait for the session to catch up
+    #[default]
+    Block,
+    // Discard the event that does not fit
+    DropNewest,
+    // Discard the oldest queued event to make room
+    DropOldest,
+}
+
+impl Overflow {
+    pub(crate) fn parse(name: &str) -> Option<Self> {
+        match name {
+            "block" => Some(Overflow::Block),
+            "drop_newest" => Some(Overflow::DropNewest),
+            "drop_oldest" => Some(Overflow::DropOldest),
+            _ => None,
+        }
+    }
+}
+
+type Item = notify::Result<Event>;
+
+struct Inner {
+    // Each item with whether it was injected, which the overflow policy never discards
+    items: VecDeque<(Item, bool)>,
+    senders: usize,
+    // Every sender was dropped, nothing more will arrive
+    disconnected: bool,
+    // The session dropped its receiver, blocked senders give up
+    closed: bool,
+}
+
+struct Queue {
+    inner: Mutex<Inner>,
+    not_empty: Condvar,
+    not_full: Condvar,
+    capacity: Option<usize>,
+    overflow: Overflow,
+    dropped: Arc<AtomicU64>,
+}
+
+#[doc = r"
+    Create the queue between the backend and the session. With a `capacity` of `None`
+    it is unbounded; otherwise `overflow` decides what happens to events that do not fit,
+    and every discarded one is counted in `dropped`.
+"]
+pub(crate) fn event_queue(
+    capacity: Option<usize>,
+    overflow: Overflow,
+    dropped: Arc<AtomicU64>,
+) -> (QueueSender, QueueReceiver) {
+    let queue = Arc::new(Queue {
+        inner: Mutex::new(Inner {
+            items: VecDeque::new(),
+            senders: 1,
+            disconnected: false,
+            closed: false,
+        }),
+        not_empty: Condvar::new(),
+        not_full: Condvar::new(),
+        capacity,
+        overflow,
+        dropped,
+    });
+    (QueueSender(queue.clone()), QueueReceiver(queue))
+}
+
+pub(crate) struct QueueSender(Arc<Queue>);
+
+impl EventHandler for QueueSender {
+    fn handle_event(&mut self, event: Item) {
+        let queue = &self.0;
+        let mut inner = queue.inner.lock().unwrap();
+        if let Some(capacity) = queue.capacity {
+            while inner.items.len() >= capacity && !inner.closed {
+                match queue.overflow {
+                    Overflow::Block => inner = queue.not_full.wait(inner).unwrap(),
+                    Overflow::DropNewest => {
+                        queue.dropped.fetch_add(1, Ordering::Relaxed);
+                        return;
+                    }
+                    Overflow::DropOldest => {
+                        queue.dropped.fetch_add(1, Ordering::Relaxed);
+                        match inner.items.iter().position(|(_, injected)| !injected) {
+                            Some(oldest) => drop(inner.items.remove(oldest)),
+                            // Only injected events are queued, so the new one has to go
+                            None => return,
+                        }
+                    }
+                }
+            }
+        }
+        if inner.closed {
+            return;
+        }
+        inner.items.push_back((event, false));
+        queue.not_empty.notify_one();
+    }
+}
+
+impl QueueSender {
+    #[doc = r"
+        Queue an event for `feed_event`. It is added even when the queue is full, so a
+        caller waiting for it to be handled is never left waiting on a discarded event.
+    "]
+    pub(crate) fn inject(&self, event: Event) {
+        let mut inner = self.0.inner.lock().unwrap();
+        if inner.closed {
+            return;
+        }
+        inner.items.push_back((Ok(event), true));
+        self.0.not_empty.notify_one();
+    }
+}
+
+impl Clone for QueueSender {
+    fn clone(&self) -> Self {
+        self.0.inner.lock().unwrap().senders += 1;
+        QueueSender(self.0.clone())
+    }
+}
+
+impl Drop for QueueSender {
+    fn drop(&mut self) {
+        let mut inner = self.0.inner.lock().unwrap();
+        inner.senders -= 1;
+        if inner.senders == 0 {
+            inner.disconnected = true;
+            self.0.not_empty.notify_all();
+        }
+    }
+}
+
+pub(crate) struct QueueReceiver(Arc<Queue>);
+
+impl QueueReceiver {
+    #[doc = r"
+        Wait up to `timeout` for the next event, like `mpsc::Receiver::recv_timeout`, along
+        with whether it was queued by `inject`.
+    "]
+    pub(crate) fn recv_timeout(&self, timeout: Duration) -> Result<(Item, bool), RecvTimeoutError> {
+        let queue = &self.0;
+        let deadline = Instant::now() + timeout;
+        let mut inner = queue.inner.lock().unwrap();
+        loop {
+            if let Some(queued) = inner.items.pop_front() {
+                queue.not_full.notify_one();
+                return Ok(queued);
+            }
+            if inner.disconnected {
+                return Err(RecvTimeoutError::Disconnected);
+            }
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Err(RecvTimeoutError::Timeout);
+            }
+            inner = queue.not_empty.wait_timeout(inner, left).unwrap().0;
+        }
+    }
+
+    #[doc = r"
+        Take every event queued so far without waiting.
+    "]
+    pub(crate) fn drain(&self) -> Vec<Item> {
+        let items = self.0.inner.lock().unwrap().items.drain(..).map(|(item, _)| item).collect();
+        self.0.not_full.notify_all();
+        items
+    }
+
+    #[doc = r"
+        Stop accepting events from the backend and release any sender blocked on a full
+        queue, so the backend can shut down. Events already queued can still be taken.
+    "]
+    pub(crate) fn close(&self) {
+        self.0.inner.lock().unwrap().closed = true;
+        self.0.not_full.notify_all();
+    }
+}
+
+impl Drop for QueueReceiver {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify::EventKind;
+
+    fn event(info: &str) -> Event {
+        Event::new(EventKind::Other).set_info(info)
+    }
+
+    fn infos(rx: &QueueReceiver) -> Vec<String> {
+        rx.drain().into_iter().map(|item| item.unwrap().info().unwrap().to_string()).collect()
+    }
+
+    #[test]
+    fn injected_events_bypass_a_full_queue() {
+        let dropped = Arc::new(AtomicU64::new(0));
+        let (mut tx, rx) = event_queue(Some(1), Overflow::DropNewest, dropped.clone());
+        tx.handle_event(Ok(event("backend")));
+        tx.inject(event("injected"));
+        tx.handle_event(Ok(event("dropped")));
+        assert_eq!(infos(&rx), ["backend", "injected"]);
+        assert_eq!(dropped.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn drop_oldest_keeps_injected_events() {
+        let dropped = Arc::new(AtomicU64::new(0));
+        let (mut tx, rx) = event_queue(Some(2), Overflow::DropOldest, dropped.clone());
+        tx.inject(event("injected"));
+        tx.handle_event(Ok(event("first")));
+        tx.handle_event(Ok(event("second")));
+        assert_eq!(infos(&rx), ["injected", "second"]);
+
+        // With only injected events queued, the new backend event is the one discarded
+        tx.inject(event("a"));
+        tx.inject(event("b"));
+        tx.handle_event(Ok(event("third")));
+        assert_eq!(infos(&rx), ["a", "b"]);
+        assert_eq!(dropped.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn closing_releases_a_blocked_sender() {
+        let (mut tx, rx) = event_queue(Some(1), Overflow::Block, Arc::new(AtomicU64::new(0)));
+        tx.handle_event(Ok(event("queued")));
+        let blocked = std::thread::spawn(move || tx.handle_event(Ok(event("blocked"))));
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(!blocked.is_finished());
+
+        rx.close();
+        blocked.join().unwrap();
+        assert_eq!(infos(&rx), ["queued"]);
+    }
+}
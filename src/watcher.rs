@@ -0,0 +1,2482 @@
+use pyo3::exceptions::{PyRuntimeError, PyTimeoutError, PyTypeError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{PyDict, PyList, PyTuple};
+use glob::Pattern;
+use notify::event::{CreateKind, ModifyKind, RemoveKind, RenameMode};
+use notify::{Config, Event, EventKind, PollWatcher, RecommendedWatcher, RecursiveMode, Watcher as _, WatcherKind};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+use std::hash::{Hash, Hasher};
+use std::path::{Component, Path, PathBuf};
+use std::process::Child;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::command::{
+    arg_batches, call_command, is_on_path, release, shell_program, substitute_placeholders, SpawnOptions,
+};
+use crate::filter::{EventFilter, Predicate};
+use crate::event::{event_attrs, event_payload, event_to_tuple, EventFormat, kind_from_selector, kind_matches, kind_path, parse_kind_selector, unknown_kind_error, Change};
+use crate::paths::{absolute_root, dedup_key, display_path, expand_roots, fd_root, mute_pattern, relative_to_roots, sync_mirror, to_extended_path, walk_files};
+use crate::queue::{event_queue, Overflow, QueueReceiver, QueueSender, DEFAULT_CAPACITY};
+
+
+#[doc = r"
+    The settings a watch session runs with, shared by `watch` and `Watcher`.
+"]
+#[derive(Clone)]
+pub(crate) struct Options {
+    pub paths: Vec<String>,
+    pub filters: Filters,
+    pub command: Option<String>,
+    pub quiet: bool,
+    pub expand_dir_events: bool,
+    pub paths_to_stdin: bool,
+    pub debug_filters: bool,
+    pub settle: Option<Duration>,
+    pub ready: Option<PyObject>,
+    pub drain_on_stop: bool,
+    pub spawn: SpawnOptions,
+    pub coalesce_writes: bool,
+    pub coalesce_window: Duration,
+    pub callbacks: Vec<PyObject>,
+    pub debounce: Option<Duration>,
+    pub max_wait: Option<Duration>,
+    pub include_attrs: bool,
+    pub rules: Vec<(Pattern, String)>,
+    pub first_match: bool,
+    pub mirror_to: Option<PathBuf>,
+    pub queue_capacity: Option<usize>,
+    pub overflow: Overflow,
+    pub event_format: EventFormat,
+    pub on_idle: Option<String>,
+    pub on_lifecycle: Option<PyObject>,
+    pub relative_paths: bool,
+    pub min_events: u32,
+    pub heartbeat: Option<Duration>,
+    pub stop_on_success: bool,
+    pub dir_fd: Option<i32>,
+    pub paths_as_args: bool,
+    pub skip_unchanged: bool,
+    pub fallback_to_poll_on_limit: bool,
+    pub on_stats: Option<PyObject>,
+    pub stats_interval: Option<Duration>,
+    pub timeout: Option<Duration>,
+    pub idle_after: Duration,
+}
+
+// The defaults `watch` runs with, which `Options::new` starts from
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            paths: Vec::new(),
+            filters: Filters::default(),
+            command: None,
+            quiet: false,
+            expand_dir_events: false,
+            paths_to_stdin: false,
+            debug_filters: false,
+            settle: None,
+            ready: None,
+            drain_on_stop: false,
+            spawn: SpawnOptions::default(),
+            coalesce_writes: false,
+            coalesce_window: Duration::from_millis(100),
+            callbacks: Vec::new(),
+            debounce: None,
+            max_wait: None,
+            include_attrs: false,
+            rules: Vec::new(),
+            first_match: false,
+            mirror_to: None,
+            queue_capacity: Some(DEFAULT_CAPACITY),
+            overflow: Overflow::Block,
+            event_format: EventFormat::Tuple,
+            on_idle: None,
+            on_lifecycle: None,
+            relative_paths: false,
+            min_events: 1,
+            heartbeat: None,
+            stop_on_success: false,
+            dir_fd: None,
+            paths_as_args: false,
+            skip_unchanged: false,
+            fallback_to_poll_on_limit: true,
+            on_stats: None,
+            stats_interval: None,
+            timeout: None,
+            idle_after: Duration::from_secs(30),
+        }
+    }
+}
+
+impl Options {
+    #[doc = r"
+        Build the options from the positional arguments and any keyword options.
+
+        Raises:
+            TypeError: If an unknown keyword option is given.
+    "]
+    pub fn new(
+        path: &str,
+        extensions: Option<Vec<String>>,
+        command: Option<&str>,
+        kwargs: Option<&PyDict>,
+    ) -> PyResult<Self> {
+        let mut options = Options {
+            paths: vec![path.to_string()],
+            filters: Filters {
+                extensions: extensions.map(normalize_extensions).transpose()?,
+                ..Default::default()
+            },
+            command: command.map(|c| c.to_string()),
+            ..Default::default()
+        };
+
+        if let Some(kwargs) = kwargs {
+            for (key, value) in kwargs {
+                options.set(key.extract()?, value)?;
+            }
+        }
+        Ok(options)
+    }
+
+    #[doc = r"
+        Check the resources the session depends on without starting it: the path or
+        pattern must resolve to something that exists, the extensions must not contain
+        path separators and, when a command is given, the shell must be available.
+
+        Returns:
+            List[str]: A description of every problem found, empty if there are none.
+    "]
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        for path in &self.paths {
+            match self.resolve(path) {
+                Ok(roots) => {
+                    for root in roots.iter().filter(|root| !root.exists()) {
+                        problems.push(format!("path '{}' does not exist", root.display()));
+                    }
+                }
+                Err(e) => problems.push(e.to_string()),
+            }
+        }
+
+        for ext in self.filters.extensions.iter().flatten() {
+            if ext.contains(['/', '\\']) {
+                problems.push(format!("extension '{}' is not a valid file extension", ext));
+            }
+        }
+
+        let commands = self
+            .command
+            .iter()
+            .chain(self.rules.iter().map(|(_, command)| command))
+            .chain(self.on_idle.iter());
+        for command in commands {
+            let shell = shell_program(command, self.spawn.exec);
+            if !is_on_path(&shell) {
+                problems.push(format!("shell '{}' was not found on PATH", shell));
+            }
+        }
+        problems
+    }
+
+    #[doc = r"
+        Resolve one of the paths to the roots to watch, relative to `dir_fd` when set.
+    "]
+    fn resolve(&self, path: &str) -> PyResult<Vec<PathBuf>> {
+        match self.dir_fd {
+            Some(fd) => {
+                // Leave out `.` components so `'.'` is the directory itself
+                let relative: PathBuf = Path::new(path)
+                    .components()
+                    .filter(|c| !matches!(c, Component::CurDir))
+                    .collect();
+                expand_roots(&fd_root(fd)?.join(relative).to_string_lossy())
+            }
+            None => expand_roots(path),
+        }
+    }
+
+    fn set(&mut self, key: &str, value: &PyAny) -> PyResult<()> {
+        if self.filters.set(key, value)? {
+            return Ok(());
+        }
+        match key {
+            "quiet" => self.quiet = value.extract()?,
+            "expand_dir_events" => self.expand_dir_events = value.extract()?,
+            "paths_to_stdin" => self.paths_to_stdin = value.extract()?,
+            "paths_as_args" => self.paths_as_args = value.extract()?,
+            "skip_unchanged" => self.skip_unchanged = value.extract()?,
+            "debounce_ms" => self.debounce = value.extract::<Option<u64>>()?.map(Duration::from_millis),
+            "min_events" => {
+                self.min_events = value.extract::<Option<u32>>()?.unwrap_or(1);
+                if self.min_events == 0 {
+                    return Err(PyValueError::new_err("min_events must be at least 1"));
+                }
+            }
+            "max_wait_ms" => self.max_wait = value.extract::<Option<u64>>()?.map(Duration::from_millis),
+            "debug_filters" => self.debug_filters = value.extract()?,
+            "drain_on_stop" => self.drain_on_stop = value.extract()?,
+            "callback" => {
+                self.callbacks = if value.is_instance_of::<PyList>() || value.is_instance_of::<PyTuple>() {
+                    value.extract()?
+                } else {
+                    vec![value.into()]
+                }
+            }
+            "coalesce_writes" => self.coalesce_writes = value.extract()?,
+            "coalesce_window_ms" => self.coalesce_window = Duration::from_millis(value.extract()?),
+            "new_window" => self.spawn.new_window = value.extract()?,
+            "niceness" => self.spawn.niceness = value.extract()?,
+            "detach" => self.spawn.detach = value.extract()?,
+            "use_shell" => self.spawn.exec = !value.extract::<bool>()?,
+            "include_attrs" => self.include_attrs = value.extract()?,
+            "relative_paths" => self.relative_paths = value.extract()?,
+            "event_format" => {
+                let name: &str = value.extract()?;
+                self.event_format = EventFormat::parse(name).ok_or_else(|| {
+                    PyValueError::new_err(format!("unknown event_format '{}', expected tuple, dict or object", name))
+                })?;
+            }
+            "rules" => {
+                let rules: Vec<(String, String)> = if value.is_instance_of::<PyDict>() {
+                    value.downcast::<PyDict>()?.items().extract()?
+                } else {
+                    value.extract()?
+                };
+                self.rules = rules
+                    .into_iter()
+                    .map(|(glob, command)| {
+                        Pattern::new(&glob)
+                            .map(|pattern| (pattern, command))
+                            .map_err(|e| PyValueError::new_err(format!("invalid rule pattern '{}': {}", glob, e)))
+                    })
+                    .collect::<PyResult<_>>()?;
+            }
+            "mirror_to" => self.mirror_to = value.extract()?,
+            "queue_capacity" => {
+                self.queue_capacity = value.extract()?;
+                if self.queue_capacity == Some(0) {
+                    return Err(PyValueError::new_err("queue_capacity must be at least 1, or None for no limit"));
+                }
+            }
+            "overflow" => {
+                let name: &str = value.extract()?;
+                self.overflow = Overflow::parse(name).ok_or_else(|| {
+                    PyValueError::new_err(format!(
+                        "unknown overflow policy '{}', expected block, drop_newest or drop_oldest",
+                        name
+                    ))
+                })?;
+            }
+            "first_match" => self.first_match = value.extract()?,
+            "ready" => self.ready = Some(value.into()),
+            "on_stats" => self.on_stats = Some(value.into()),
+            "stats_interval_secs" => {
+                let secs: Option<u64> = value.extract()?;
+                if secs == Some(0) {
+                    return Err(PyValueError::new_err("stats_interval_secs must be at least 1"));
+                }
+                self.stats_interval = secs.map(Duration::from_secs);
+            }
+            "fallback_to_poll_on_limit" => self.fallback_to_poll_on_limit = value.extract()?,
+            "dir_fd" => self.dir_fd = value.extract()?,
+            "stop_on_success" => self.stop_on_success = value.extract()?,
+            "timeout_secs" => {
+                let secs: Option<f64> = value.extract()?;
+                self.timeout = secs
+                    .map(Duration::try_from_secs_f64)
+                    .transpose()
+                    .map_err(|_| PyValueError::new_err("timeout_secs must be a non-negative number"))?;
+            }
+            "heartbeat_secs" => {
+                let secs: Option<u64> = value.extract()?;
+                if secs == Some(0) {
+                    return Err(PyValueError::new_err("heartbeat_secs must be at least 1"));
+                }
+                self.heartbeat = secs.map(Duration::from_secs);
+            }
+            "on_idle" => self.on_idle = value.extract()?,
+            "on_lifecycle" => self.on_lifecycle = Some(value.into()),
+            "idle_after_ms" => self.idle_after = Duration::from_millis(value.extract()?),
+            "settle_ms" => self.settle = value.extract::<Option<u64>>()?.map(Duration::from_millis),
+            _ => return Err(PyTypeError::new_err(format!("unexpected keyword argument '{}'", key))),
+        }
+        Ok(())
+    }
+
+    #[doc = r"
+        Report a lifecycle transition to `on_lifecycle`, if set. An exception it raises is
+        printed and does not affect the watch.
+    "]
+    fn lifecycle(&self, tag: &str, detail: Option<String>) {
+        if let Some(callback) = &self.on_lifecycle {
+            Python::with_gil(|py| {
+                if let Err(e) = callback.call1(py, (tag, detail)) {
+                    e.print(py);
+                }
+            });
+        }
+    }
+}
+
+#[doc = r"
+    The options that decide which events are reported. The running session reads them
+    from `State`, so `Watcher.reconfigure` can swap them without restarting the watch.
+"]
+#[derive(Clone, Default)]
+pub(crate) struct Filters {
+    pub extensions: Option<Vec<String>>,
+    pub filenames: Option<Vec<String>>,
+    pub event_kinds: Option<Vec<Vec<String>>>,
+    pub ignore_root_events: bool,
+    pub ignore: Vec<Pattern>,
+    pub include: Option<Vec<Pattern>>,
+    pub filter: Option<Predicate>,
+}
+
+impl Filters {
+    #[doc = r"
+        Whether `event_kinds` asks for metadata changes, in which case file modes are
+        tracked so permission changes can be reported.
+    "]
+    fn tracks_modes(&self) -> bool {
+        let metadata = ["Modify".to_string(), "Metadata".to_string()];
+        self.event_kinds.iter().flatten().any(|selector| selector.starts_with(&metadata))
+    }
+
+    #[doc = r"
+        Set a filter option by its keyword.
+
+        Returns:
+            bool: Whether `key` names a filter option.
+    "]
+    fn set(&mut self, key: &str, value: &PyAny) -> PyResult<bool> {
+        match key {
+            "extensions" => {
+                let extensions: Option<Vec<String>> = value.extract()?;
+                self.extensions = extensions.map(normalize_extensions).transpose()?;
+            }
+            "filenames" => self.filenames = value.extract()?,
+            "event_kinds" => {
+                let selectors: Option<Vec<String>> = value.extract()?;
+                self.event_kinds = selectors
+                    .map(|selectors| {
+                        selectors
+                            .iter()
+                            .map(|selector| {
+                                parse_kind_selector(selector).ok_or_else(|| unknown_kind_error(selector))
+                            })
+                            .collect::<PyResult<_>>()
+                    })
+                    .transpose()?;
+            }
+            "ignore_root_events" => self.ignore_root_events = value.extract()?,
+            "ignore" => {
+                let globs: Option<Vec<String>> = value.extract()?;
+                self.ignore = globs.map(|globs| parse_globs(key, globs)).transpose()?.unwrap_or_default();
+            }
+            "filter" => {
+                let filter: Option<PyRef<EventFilter>> = value.extract()?;
+                self.filter = filter.map(|filter| filter.predicate.clone());
+            }
+            "include" => {
+                let globs: Option<Vec<String>> = value.extract()?;
+                self.include = globs.map(|globs| parse_globs(key, globs)).transpose()?;
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+}
+
+#[doc = r"
+    Compile the glob patterns given for the `ignore` or `include` option.
+
+    Raises:
+        ValueError: Naming the option and the first invalid pattern.
+"]
+fn parse_globs(option: &str, globs: Vec<String>) -> PyResult<Vec<Pattern>> {
+    globs
+        .iter()
+        .map(|glob| {
+            Pattern::new(glob)
+                .map_err(|e| PyValueError::new_err(format!("invalid {} pattern '{}': {}", option, glob, e)))
+        })
+        .collect()
+}
+
+#[doc = r"
+    Normalize the `extensions` argument: surrounding whitespace and a leading dot are
+    removed, so `' .css'` matches like `'css'`.
+
+    Raises:
+        ValueError: Listing the entries that are empty once trimmed, e.g. from splitting
+        an empty config value.
+"]
+pub(crate) fn normalize_extensions(extensions: Vec<String>) -> PyResult<Vec<String>> {
+    let empty: Vec<&String> = extensions
+        .iter()
+        .filter(|ext| ext.trim().trim_start_matches('.').is_empty())
+        .collect();
+    if !empty.is_empty() {
+        return Err(PyValueError::new_err(format!("extensions must not be empty, got {:?}", empty)));
+    }
+
+    let normalized = extensions.iter().map(|ext| ext.trim().trim_start_matches('.').to_string()).collect();
+    Ok(normalized)
+}
+
+#[doc = r"
+    State the event loop shares with the Python-facing `Watcher`.
+"]
+#[derive(Default)]
+pub(crate) struct State {
+    pub last_pid: Mutex<Option<u32>>,
+    pub stopping: AtomicBool,
+    pub muted: Mutex<Vec<Pattern>>,
+    pub callbacks: Mutex<Vec<PyObject>>,
+    pub filters: Mutex<Filters>,
+    pub counters: Mutex<Counters>,
+    // The backend the running (or last) session watches with, for `Watcher.backend`
+    pub backend: Mutex<Option<&'static str>>,
+    // Shared with the backend's event queue, which counts what it discards
+    pub dropped_events: Arc<AtomicU64>,
+    // The running session's queue, for `feed_event`
+    injector: Mutex<Option<QueueSender>>,
+    // How many events were fed and how many of those the session has handled
+    fed: Mutex<(u64, u64)>,
+    fed_handled: Condvar,
+    // Which commands finished, for `wait_for_next_run`
+    runs: Mutex<Runs>,
+    run_finished: Condvar,
+}
+
+#[doc = r"
+    The latest command to finish, numbered in the order commands were spawned, with its
+    exit code; and how many times `Watcher.run` has returned, which also wakes waiters.
+"]
+#[derive(Default)]
+struct Runs {
+    finished: Option<(u64, Option<i32>)>,
+    watches_ended: u64,
+}
+
+#[doc = r"
+    What the watcher has done over its lifetime, for `Watcher.stats` and `on_stats`.
+"]
+#[derive(Default)]
+pub(crate) struct Counters {
+    events_seen: u64,
+    events_filtered: u64,
+    commands_run: u64,
+    command_failures: u64,
+    // Commands that finished, and their total run time
+    commands_finished: u64,
+    command_time: Duration,
+}
+
+impl State {
+    #[doc = r"
+        The watcher's counters as a dict: `events_seen`, `events_filtered`,
+        `dropped_events`, `commands_run`, `command_failures` and `avg_command_secs`.
+    "]
+    fn stats<'py>(&self, py: Python<'py>) -> PyResult<&'py PyDict> {
+        let counters = self.counters.lock().unwrap();
+        let stats = PyDict::new(py);
+        stats.set_item("events_seen", counters.events_seen)?;
+        stats.set_item("events_filtered", counters.events_filtered)?;
+        stats.set_item("dropped_events", self.dropped_events.load(Ordering::Relaxed))?;
+        stats.set_item("commands_run", counters.commands_run)?;
+        stats.set_item("command_failures", counters.command_failures)?;
+        let average = (counters.commands_finished > 0)
+            .then(|| counters.command_time.as_secs_f64() / counters.commands_finished as f64);
+        stats.set_item("avg_command_secs", average)?;
+        Ok(stats)
+    }
+
+    pub(crate) fn for_options(options: &Options) -> Self {
+        State {
+            callbacks: Mutex::new(options.callbacks.clone()),
+            filters: Mutex::new(options.filters.clone()),
+            ..Default::default()
+        }
+    }
+
+    #[doc = r"
+        Queue an event for the running session and wait until it has been handled, or
+        the session has ended without handling it.
+
+        Raises:
+            RuntimeError: If no session is running.
+    "]
+    fn feed(&self, event: Event) -> PyResult<()> {
+        let ticket = {
+            // Counted and queued together under the injector lock, so tickets follow queue
+            // order and a session ending in between cannot miss one
+            let injector = self.injector.lock().unwrap();
+            let Some(tx) = injector.as_ref() else {
+                return Err(PyRuntimeError::new_err("the watcher is not running"));
+            };
+            let mut fed = self.fed.lock().unwrap();
+            fed.0 += 1;
+            tx.inject(event);
+            fed.0
+        };
+        let fed = self.fed.lock().unwrap();
+        drop(self.fed_handled.wait_while(fed, |fed| fed.1 < ticket).unwrap());
+        Ok(())
+    }
+
+    #[doc = r"
+        Record that the session finished handling a fed event, or with `all` that it
+        will not handle the rest, and wake the `feed_event` calls waiting on them.
+    "]
+    fn mark_fed(&self, all: bool) {
+        let mut fed = self.fed.lock().unwrap();
+        fed.1 = if all { fed.0 } else { fed.1 + 1 };
+        self.fed_handled.notify_all();
+    }
+
+    #[doc = r"
+        Record that the command spawned as number `run` exited, and wake the
+        `wait_for_next_run` calls. An earlier command finishing late does not replace a
+        later one.
+    "]
+    fn mark_finished(&self, run: u64, code: Option<i32>) {
+        let mut runs = self.runs.lock().unwrap();
+        if runs.finished.is_none_or(|(latest, _)| run > latest) {
+            runs.finished = Some((run, code));
+        }
+        self.run_finished.notify_all();
+    }
+
+    fn mark_watch_ended(&self) {
+        self.runs.lock().unwrap().watches_ended += 1;
+        self.run_finished.notify_all();
+    }
+
+    #[doc = r"
+        The muted pattern an event's path matches, if any.
+    "]
+    fn muted_by(&self, event: &Event) -> Option<String> {
+        let muted = self.muted.lock().unwrap();
+        let path = PathBuf::from(event_path(event));
+        let absolute = std::path::absolute(&path).unwrap_or_else(|_| path.clone());
+
+        muted
+            .iter()
+            .find(|pattern| pattern.matches_path(&path) || pattern.matches_path(&absolute))
+            .map(|pattern| pattern.as_str().to_string())
+    }
+}
+
+#[pyclass]
+#[doc = r"
+    Watcher(path: str, extensions: Optional[list[str]] = None, command: Optional[str] = None, **options)
+
+    A reusable watch session. Takes the same arguments as `watch`, but only starts
+    monitoring once `run` is called, and exposes information about the session while
+    it is running.
+
+    Options:
+        callback (Optional[Callable | list[Callable]]): Called with a `(kind, path)` tuple for
+        every change that passes the filters. Given a list, every callback is called in
+        order; `add_callback` and `remove_callback` change the list while watching. An
+        exception raised by a callback is printed and does not stop the others.
+        include_attrs (bool): Call the callback with `(kind, path, attrs)` instead, where
+        `attrs` is a dict of the extra attributes notify attached to the event: `detail`
+        (the full kind, e.g. `Modify(Name(From))`), `tracker` (pairs the halves of a rename
+        on Linux), `flag` (`rescan` after dropped events), `info` (macOS notes such as
+        `mount`), `source` and `process_id` (not set by current backends). Missing values
+        are `None`.
+        relative_paths (bool): Report paths relative to the watched root, e.g. `src/main.rs`
+        rather than an absolute path, to callbacks and commands alike (stdin, environment
+        and `{events_json}`). With several roots the longest one containing the path is
+        stripped; a path outside every root, or a root itself, is reported as is.
+        event_format (str): What callbacks receive for each change: `tuple` (the default)
+        for `(kind, path)` as above, `dict` for `{'kind': ..., 'paths': [...], 'timestamp':
+        ...}` or `object` for an `Event` with the same fields as attributes. With
+        `include_attrs` the dict gains an `attrs` key and the `Event` its `attrs` attribute.
+        Unlike the tuple, both carry every path of the event, e.g. both sides of a rename.
+        rules (Optional[dict[str, str] | list[tuple[str, str]]]): Glob pattern to command
+        pairs, e.g. `{'**/*.css': 'npx tailwindcss', '**/*.py': 'pytest'}`. A change runs
+        the command of every rule whose pattern matches its path, either as reported or
+        relative to the watched root, in addition to `command`. Rules are batched, throttled
+        and debounced like `command`.
+        first_match (bool): Run only the first rule whose pattern matches a change instead
+        of every matching rule. Rules are evaluated in the order given (insertion order for
+        a dict), so put specific rules above broad catch-alls. Does not affect `command`.
+        event_kinds (Optional[list[str]]): Only report events of these kinds: `Access`,
+        `Create`, `Modify`, `Remove` or `Other`, optionally narrowed with `::`, e.g.
+        `Modify::Metadata` for permission and timestamp changes, `Modify::Data` for content
+        edits or `Create::Folder`. Each level must be a notify variant name (e.g.
+        `Modify::Name::From`); an unknown one raises a `ValueError`. `Any` alone matches
+        only events the backend could not classify at all, not every event. Backends that
+        cannot tell the sub-kinds apart (Windows reports every modification as plain
+        `Modify`) match any narrower selector, so there `Modify::Metadata` degrades to
+        `Modify`. With a `Modify::Metadata` selector and `include_attrs`, metadata events
+        carry `mode_before` and `mode_after` (Unix permission bits) in `attrs`. The modes
+        are read when the event is processed, so `mode_before` is the mode seen for the
+        path's previous event and is best effort under rapid changes; both are `None` where
+        unknown and always on Windows.
+        fallback_to_poll_on_limit (bool): When registering the tree runs into the OS limit
+        on watched directories (`max_user_watches` for inotify on Linux, common with large
+        repositories), print a warning and watch by polling once a second instead, which
+        works at any size but costs CPU and reacts more slowly. When `False` the watch
+        fails with a `RuntimeError` giving the limit and how to raise it. Defaults to
+        `True`. Directories created later that no longer fit are not watched; notify does
+        not report those.
+        dir_fd (Optional[int]): Watch an already-open directory descriptor instead of
+        opening the directory by path, e.g. one handed to a sandboxed process (`os.open(dir,
+        os.O_RDONLY)`). `path` is then resolved relative to it, so pass `'.'` to watch the
+        directory itself. Linux only: the watch goes through `/proc/self/fd`, which must be
+        mounted, and reported paths start with `/proc/self/fd/<fd>` (use `relative_paths`
+        for portable ones). Raises `ValueError` if the descriptor is not an open directory
+        and `NotImplementedError` on other platforms. The descriptor must stay open while
+        watching.
+        mirror_to (Optional[str]): Keep a copy of every changed file in this directory, at
+        the same path relative to the watched root: files are copied as they change,
+        directories are created, and copies are deleted when the original is removed. A
+        watched file is copied into the directory under its own name. Changes inside the
+        mirror directory itself are never mirrored. Copy errors are reported like watch
+        errors and do not stop the watch.
+        filter (Optional[EventFilter]): A structured filter combining extension, path-prefix
+        and kind predicates with `all_of` and `any_of`, for conditions the flat lists cannot
+        express, e.g. `EventFilter.all_of(EventFilter.extension('rs', 'toml'),
+        EventFilter.under('src'))`. It applies in addition to the other filters.
+        ignore (Optional[list[str]]): Glob patterns for paths to leave out, e.g.
+        `['**/node_modules/**', '**/*.tmp']`. A pattern matches the path as reported or
+        relative to the watched root. Raises `ValueError` for an invalid pattern.
+        include (Optional[list[str]]): Glob patterns a path must match one of to be
+        reported, matched like `ignore`. `ignore` wins when both match.
+        filenames (Optional[list[str]]): Only report changes to files whose name is exactly
+        one of these, anywhere in the tree, e.g. `['Cargo.toml', 'package.json']`. When
+        `extensions` is also given a change must satisfy both.
+        quiet (bool): Suppress the informational output (the watching banner, detected
+        changes, commands being run and heartbeats). Errors are still reported.
+        on_stats (Optional[Callable]): Called every `stats_interval_secs` with the current
+        counters, the same dict as `Watcher.stats`, e.g. to feed a monitoring system. It is
+        driven by the event loop's wake-ups, so it keeps firing during quiet periods and is
+        within a tenth of a second of the interval. Exceptions it raises are printed.
+        stats_interval_secs (Optional[int]): How often `on_stats` is called, at least 1.
+        Neither does anything without the other.
+        heartbeat_secs (Optional[int]): Print `still watching <paths> (N events so far)`
+        whenever this many seconds pass without any event arriving, so a rarely changing
+        tree still shows the watch is alive. N counts the events reported so far, i.e.
+        those that passed the filters. Must be at least 1.
+        expand_dir_events (bool): Report a directory that appears as a `Create` event for
+        every file inside it, and a directory that disappears as a `Remove` event for every
+        file the session has seen below it, so bulk folder operations look like individual
+        file changes. A new empty directory is reported as is. Files touched right after the
+        directory appears may be reported twice.
+        paths_to_stdin (bool): Write the paths that changed since the previous run, one per
+        line, to the command's stdin instead of leaving it attached to the terminal. The
+        command must actually read its stdin for this to have any effect.
+        paths_as_args (bool): Append the paths that changed since the previous run, without
+        duplicates, as arguments after the command's own, so `prettier --write` runs once
+        as `prettier --write a.js b.js c.js`. When the command line would exceed 8191
+        characters (the `cmd.exe` limit) the paths are split over as many runs as needed,
+        each started straight after the other. With `use_shell=False` each path is passed
+        as exactly one argument; through a shell they are appended to the command text
+        unquoted, so paths containing spaces are split. With `paths_to_stdin`, each run
+        gets its own share of the paths on stdin as well.
+        skip_unchanged (bool): Skip a run when the files it is for have exactly the
+        contents they had the last time the command ran for that same set of files, e.g.
+        after an editor rewrites a file without changing it. The contents of the whole
+        batch (everything pending once the throttle or debounce is over) are hashed
+        together, per command. Removing any of the files forgets what was recorded for
+        the sets containing it. Only useful for deterministic commands, whose result
+        depends on nothing but those files. Off by default.
+        debug_filters (bool): Print a line for every event a filter drops, naming the rule
+        responsible, e.g. `skipped src/app.js (extension js not in set)`.
+        debounce_ms (Optional[int]): Run the command once no qualifying event has arrived for
+        this many milliseconds, instead of at most once a second as changes come in.
+        max_wait_ms (Optional[int]): With `debounce_ms`, run the command no later than this
+        many milliseconds after the first change it would handle, even if events never
+        stop arriving, so sustained activity still gets periodic runs. It overrides
+        `settle_ms`: once the limit passes, the command starts even while events are still
+        arriving.
+        min_events (Optional[int]): Only run the command once at least this many qualifying
+        events (those that pass the filters) have accumulated since it last ran, so a single
+        stray event does not trigger a rebuild. The count resets after every run. Until it
+        is reached the command is not due at all: the debounce, `settle_ms` and `max_wait_ms`
+        timers only apply once enough events arrived, and pending changes simply wait for
+        more. Events keep resetting the `on_idle` period either way. `drain_on_stop` runs
+        pending changes regardless of the count. Defaults to 1.
+        settle_ms (Optional[int]): Once the command is due to run, wait until no qualifying
+        event has arrived for this many milliseconds before starting it. Any event during
+        the wait restarts it, so the command starts on a quiet filesystem, unless
+        `max_wait_ms` runs it during sustained activity.
+        on_idle (Optional[str]): A command to run once the watched tree has been quiet for
+        `idle_after_ms`, e.g. a backup after editing stops. It runs once per quiet period:
+        the next qualifying change (one that passes the filters) starts a new period, and
+        the session's start counts as the beginning of the first. It is independent of
+        `command`, which still runs for changes as usual; the idle command gets no changed
+        paths (its placeholders expand as for an empty batch) and is not throttled or
+        debounced. Changes the idle command itself makes in the tree end the quiet period
+        like any other.
+        idle_after_ms (int): How long without qualifying changes counts as idle. Defaults
+        to 30000.
+        on_lifecycle (Optional[Callable]): Called as `on_lifecycle(tag, detail)` at each
+        transition of the watch, separately from the change callbacks, e.g. to emit metrics
+        or drive a status indicator. Tags are `watch-started` once every path is watched
+        (right after `ready` is set; `detail` lists the paths), `watch-error` for an error
+        from the backend, a command that could not be started, a failed `mirror_to` copy
+        or an error that ends the watch (`detail` describes it), `watch-stopped` when a
+        started watch ends (`detail` is `stop`, `success` with `stop_on_success`, `error`
+        or `disconnected`) and `backend-reopened` when the watch switched backends
+        (`detail` is `poll` after `fallback_to_poll_on_limit`). Exceptions it raises are
+        printed and ignored.
+        ready (Optional[threading.Event]): Set once every path is being watched, so no
+        change made after it is set can be missed. Any object with a `set()` method works.
+        With `watch_many` it is set once, when every mapping is being watched.
+        ignore_root_events (bool): Drop events reported for a watched root directory itself
+        rather than for something below it. Some backends (notably FSEvents on macOS and
+        ReadDirectoryChangesW on Windows) emit `Access`/`Modify` metadata events on the
+        directory node for every operation inside it. Off by default.
+        stop_on_success (bool): End the watch the first time a command exits with status 0,
+        e.g. to watch and rebuild until the build passes. The exit status is checked on
+        each pass of the event loop, a tenth of a second at most after the command ends;
+        detached commands are not waited on and never count, nor does `on_idle`. Pair it
+        with `timeout_secs` so a build that never passes gives up eventually.
+        timeout_secs (Optional[float]): End the watch after this many seconds by raising
+        `TimeoutError`, whether or not anything changed. Commands still running are left
+        alone.
+        drain_on_stop (bool): When `stop` is called, stop accepting new events but process
+        the ones already queued, running the command one last time if they warrant it,
+        before `run` returns. Otherwise queued events are discarded.
+        niceness (Optional[int]): Run the command at a lower (positive) or higher (negative)
+        scheduling priority. On Unix this is the `nice` value, clamped to -20..=19. On
+        Windows, a niceness of 10 or more maps to the idle priority class, 1 to 9 to below
+        normal, -1 to -9 to above normal and -10 or less to high.
+        coalesce_writes (bool): Treat a `Create` followed by `Modify` events for the same
+        path within `coalesce_window_ms` as a single `Create`, so a freshly written file is
+        reported once.
+        coalesce_window_ms (int): How long after a `Create` its `Modify` events are folded
+        into it. Defaults to 100.
+        new_window (bool): Run the command in a new terminal window instead of sharing
+        xpectate's console: `cmd /C start` on Windows, Terminal.app (via `osascript`) on
+        macOS and `x-terminal-emulator -e` on Linux. The command's output is not captured
+        in this mode, and `paths_to_stdin` feeds the launcher rather than the command.
+        detach (bool): Fire and forget: start every command fully detached, for launching
+        something that should outlive the watch, such as a background daemon. On Unix it
+        runs in a new session (`setsid`), on Windows as a `DETACHED_PROCESS` in a new process
+        group. Its stdio is redirected to the null device (stdin still carries the paths with
+        `paths_to_stdin`), and xpectate does not keep its handle, wait for it or stop it;
+        `last_pid` still reports its PID.
+        use_shell (bool): Run commands through `pwsh -Command` (the default). When `False`
+        the first word of the command is executed directly as the program and the remaining
+        words are passed to it as literal arguments, split on whitespace with no quoting,
+        globbing, variables or pipes. Placeholders are substituted within each word after
+        the split, so a path containing spaces stays one argument. This avoids shell
+        injection through placeholders like `{events_json}`, and the cost of starting a
+        shell. A `#!shell` prefix still picks its shell.
+        queue_capacity (Optional[int]): How many backend events may wait for the session
+        before `overflow` applies, so a huge burst (e.g. checking out a large branch) cannot
+        grow memory without bound. Defaults to 10000; `None` removes the limit.
+        overflow (str): What happens to an event that arrives while the queue is full:
+        `block` (the default) holds the backend until there is room, `drop_newest` discards
+        the new event and `drop_oldest` discards the oldest queued one. Discarded events
+        are counted in `stats['dropped_events']`. While blocked the OS buffers events
+        itself and may report an overflow (a `rescan` flag) once it is full too.
+"]
+pub struct Watcher {
+    options: Options,
+    state: Arc<State>,
+}
+
+impl Watcher {
+    pub(crate) fn from_options(options: Options) -> Self {
+        let state = State::for_options(&options);
+        Watcher {
+            options,
+            state: Arc::new(state),
+        }
+    }
+}
+
+#[pymethods]
+impl Watcher {
+    #[new]
+    #[pyo3(signature = (path, extensions=None, command=None, **options))]
+    pub fn new(
+        path: &str,
+        extensions: Option<Vec<String>>,
+        command: Option<&str>,
+        options: Option<&PyDict>,
+    ) -> PyResult<Self> {
+        Ok(Watcher::from_options(Options::new(path, extensions, command, options)?))
+    }
+
+    #[doc = r"
+        run() -> None
+
+        Start monitoring and block until the watch ends. The GIL is released while
+        waiting, so other Python threads can keep inspecting the watcher.
+    "]
+    pub fn run(&self, py: Python) -> PyResult<()> {
+        let result = py.allow_threads(|| run(&self.options, &self.state));
+        self.state.stopping.store(false, Ordering::SeqCst);
+        self.state.mark_watch_ended();
+        result
+    }
+
+    #[doc = r"
+        stop() -> None
+
+        Ask a running watch to end. `run` returns within a fraction of a second, after
+        draining queued events first if `drain_on_stop` is set.
+    "]
+    fn stop(&self) {
+        self.state.stopping.store(true, Ordering::SeqCst);
+    }
+
+    #[doc = r"
+        add_callback(callback: Callable) -> None
+
+        Register another change callback, called after the existing ones. Safe to call
+        from any thread, including while `run` is blocking another.
+    "]
+    fn add_callback(&self, callback: PyObject) {
+        self.state.callbacks.lock().unwrap().push(callback);
+    }
+
+    #[doc = r"
+        remove_callback(callback: Callable) -> bool
+
+        Unregister the first callback equal to `callback`.
+
+        Returns:
+            bool: Whether a callback was removed.
+    "]
+    fn remove_callback(&self, py: Python, callback: PyObject) -> PyResult<bool> {
+        // Compared on a snapshot, since `__eq__` can release the GIL while the event loop
+        // holds it waiting for the lock
+        let snapshot = self.state.callbacks.lock().unwrap().clone();
+        for registered in snapshot {
+            if registered.as_ref(py).eq(&callback)? {
+                let mut callbacks = self.state.callbacks.lock().unwrap();
+                // The very object that matched, in case the list changed in the meantime
+                if let Some(i) = callbacks.iter().position(|c| c.is(&registered)) {
+                    callbacks.remove(i);
+                    return Ok(true);
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    #[pyo3(signature = (**options))]
+    #[doc = r"
+        reconfigure(**options) -> None
+
+        Change the filters while the watch keeps running, without re-registering the
+        watched paths or missing events: `extensions`, `filenames`, `event_kinds`,
+        `filter`, `ignore`, `include` and `ignore_root_events`, with the same values as at
+        construction. The new filters are swapped in together and apply from the next
+        event; options not given keep their current value. Safe to call from any thread,
+        and also before `run`.
+
+        Raises:
+            TypeError: If an option other than a filter is given; the rest, such as the
+            paths or the command, still need a new `Watcher`.
+            ValueError: If a value is invalid, in which case nothing is changed.
+    "]
+    fn reconfigure(&self, options: Option<&PyDict>) -> PyResult<()> {
+        let mut filters = self.state.filters.lock().unwrap().clone();
+        for (key, value) in options.into_iter().flatten() {
+            let key: &str = key.extract()?;
+            if !filters.set(key, value)? {
+                return Err(PyTypeError::new_err(format!(
+                    "'{}' cannot be changed by reconfigure, only extensions, filenames, event_kinds, filter, ignore, include and ignore_root_events",
+                    key
+                )));
+            }
+        }
+        *self.state.filters.lock().unwrap() = filters;
+        Ok(())
+    }
+
+    #[doc = r"
+        mute(path: str) -> None
+
+        Stop changes to `path` from being reported or triggering the command until it is
+        unmuted. `path` is either an exact path or a glob pattern such as `**/*.map`; a
+        path that exists is taken as exact even if it contains glob characters, e.g.
+        `app/[id]/page.tsx`. The muted set is shared with the event loop behind a lock,
+        so this can be called from any thread while `run` is blocking another.
+
+        Raises:
+            ValueError: If the glob pattern is invalid.
+    "]
+    fn mute(&self, path: &str) -> PyResult<()> {
+        let pattern = mute_pattern(path)?;
+        let mut muted = self.state.muted.lock().unwrap();
+        if !muted.contains(&pattern) {
+            muted.push(pattern);
+        }
+        Ok(())
+    }
+
+    #[doc = r"
+        unmute(path: str) -> bool
+
+        Undo a previous `mute` with the same path or pattern. Safe to call from any thread.
+
+        Returns:
+            bool: Whether `path` was muted.
+    "]
+    fn unmute(&self, path: &str) -> PyResult<bool> {
+        let pattern = mute_pattern(path)?;
+        let mut muted = self.state.muted.lock().unwrap();
+        let before = muted.len();
+        muted.retain(|p| p != &pattern);
+        Ok(muted.len() != before)
+    }
+
+    #[doc = r"
+        feed_event(kind: str, path: str) -> None
+
+        For testing: inject a synthetic event into the running watch, as if the backend
+        had reported it, and wait until it has been handled. It goes through the same
+        mute check, filters, coalescing, callbacks, debounce and command dispatch as a real
+        event, so that logic can be exercised deterministically without touching the
+        filesystem or racing the backend. The event's `attrs['info']` is `injected`. It is
+        queued even when the queue is full, whatever the `overflow` policy, and the call
+        returns without it being handled if the watch ends first.
+
+        Arguments:
+            kind (str): `Access`, `Create`, `Modify`, `Remove` or `Other`, optionally
+            narrowed like `event_kinds`, e.g. `Modify::Data`.
+            path (str): The path the event is about, as the backend would report it (an
+            absolute path below the watched root).
+
+        Raises:
+            ValueError: If `kind` is not a known event kind.
+            RuntimeError: If the watcher is not running.
+    "]
+    fn feed_event(&self, py: Python, kind: &str, path: &str) -> PyResult<()> {
+        let event = synthetic_event(kind, path)?;
+        // Callbacks need the GIL, which is released while waiting
+        py.allow_threads(|| self.state.feed(event))
+    }
+
+    #[pyo3(signature = (timeout_secs=None))]
+    #[doc = r"
+        wait_for_next_run(timeout_secs: Optional[float] = None) -> Optional[int]
+
+        Block until a command spawned after this call has finished, e.g. to trigger a test
+        run from another thread and then read its output. The watch runs in another
+        thread; the GIL is released while waiting. A command already running when this is
+        called does not count, nor does the `on_idle` command. Detached commands never
+        finish as far as the watcher knows.
+
+        Arguments:
+            timeout_secs (Optional[float]): Give up after this many seconds. Waits forever
+            if `None`.
+
+        Returns:
+            Optional[int]: The command's exit code, or `None` if it was killed by a signal.
+
+        Raises:
+            TimeoutError: If no command finished within `timeout_secs`.
+            RuntimeError: If `run` returned before one did.
+            ValueError: If `timeout_secs` is negative.
+    "]
+    fn wait_for_next_run(&self, py: Python, timeout_secs: Option<f64>) -> PyResult<Option<i32>> {
+        let timeout = timeout_secs
+            .map(Duration::try_from_secs_f64)
+            .transpose()
+            .map_err(|_| PyValueError::new_err("timeout_secs must be a non-negative number"))?;
+        // Commands are numbered as they are counted, so the next one spawned is after this
+        let after = self.state.counters.lock().unwrap().commands_run;
+        py.allow_threads(|| {
+            let mut runs = self.state.runs.lock().unwrap();
+            let ended = runs.watches_ended;
+            let deadline = timeout.map(|t| Instant::now() + t);
+            loop {
+                match runs.finished {
+                    Some((run, code)) if run > after => return Ok(code),
+                    _ if runs.watches_ended != ended => {
+                        return Err(PyRuntimeError::new_err("the watch ended before a command finished"))
+                    }
+                    _ => {}
+                }
+                runs = match deadline {
+                    None => self.state.run_finished.wait(runs).unwrap(),
+                    Some(deadline) => {
+                        let left = deadline.saturating_duration_since(Instant::now());
+                        if left.is_zero() {
+                            return Err(PyTimeoutError::new_err(format!(
+                                "no command finished within {} seconds",
+                                timeout_secs.unwrap_or_default()
+                            )));
+                        }
+                        self.state.run_finished.wait_timeout(runs, left).unwrap().0
+                    }
+                };
+            }
+        })
+    }
+
+    #[getter]
+    #[doc = r"
+        The backend the watch is using, e.g. `inotify`, or `poll` once
+        `fallback_to_poll_on_limit` switched to polling; unlike `backend_info`, which
+        describes the default. `None` until `run` has started watching; after the watch
+        ends it still names the backend it used.
+    "]
+    fn backend(&self) -> Option<&'static str> {
+        *self.state.backend.lock().unwrap()
+    }
+
+    #[getter]
+    #[doc = r"
+        The PID of the most recently spawned command, or `None` if no command has run yet.
+    "]
+    fn last_pid(&self) -> Option<u32> {
+        *self.state.last_pid.lock().unwrap()
+    }
+
+    #[getter]
+    #[doc = r"
+        Counters for the watcher's lifetime, as a dict: `events_seen` (events received
+        from the backend), `events_filtered` (those a filter, mute or coalescing dropped),
+        `dropped_events` (those discarded by the `overflow` policy because the queue was
+        full), `commands_run`, `command_failures` (commands that exited with a non-zero
+        status or could not be started) and `avg_command_secs` (the mean run time of the
+        commands that finished, or `None` before any did). Detached commands are counted
+        as run but never finish.
+    "]
+    fn stats<'py>(&self, py: Python<'py>) -> PyResult<&'py PyDict> {
+        self.state.stats(py)
+    }
+}
+
+#[pyclass]
+#[doc = r"
+    The decorator returned by `on_change`. Applying it to a function returns a `Watcher`
+    that calls the function for every change; call `run()` on it to start watching.
+"]
+pub struct OnChange {
+    options: Options,
+}
+
+impl OnChange {
+    pub(crate) fn new(options: Options) -> Self {
+        OnChange { options }
+    }
+}
+
+#[pymethods]
+impl OnChange {
+    fn __call__(&self, func: PyObject) -> Watcher {
+        let mut options = self.options.clone();
+        options.callbacks = vec![func];
+        Watcher::from_options(options)
+    }
+}
+
+#[doc = r"
+    The short name of a notify backend, e.g. `inotify` or `poll`.
+"]
+pub(crate) fn backend_name(kind: WatcherKind) -> &'static str {
+    match kind {
+        WatcherKind::Inotify => "inotify",
+        WatcherKind::Fsevent => "fsevents",
+        WatcherKind::Kqueue => "kqueue",
+        WatcherKind::PollWatcher => "poll",
+        WatcherKind::ReadDirectoryChangesWatcher => "windows",
+        WatcherKind::NullWatcher => "null",
+        _ => "unknown",
+    }
+}
+
+#[doc = r"
+    The backend a watch session uses and the settings it is configured with.
+
+    Returns:
+        Tuple[str, Config]: The backend name and its notify config.
+"]
+pub(crate) fn backend() -> (&'static str, Config) {
+    (backend_name(RecommendedWatcher::kind()), Config::default())
+}
+
+/// How often the polling backend used after hitting the watch limit rescans the tree.
+const POLL_FALLBACK_INTERVAL: Duration = Duration::from_secs(1);
+
+#[doc = r"
+    Create the backend and register every root with it: the platform's native backend,
+    or with `poll` notify's polling one.
+"]
+fn open_watcher(tx: &QueueSender, roots: &[PathBuf], poll: bool) -> notify::Result<Box<dyn notify::Watcher>> {
+    let mut watcher: Box<dyn notify::Watcher> = if poll {
+        let config = backend().1.with_poll_interval(POLL_FALLBACK_INTERVAL);
+        Box::new(PollWatcher::new(tx.clone(), config)?)
+    } else {
+        Box::new(RecommendedWatcher::new(tx.clone(), backend().1)?)
+    };
+    for root in roots {
+        watcher.watch(root, RecursiveMode::Recursive)?;
+    }
+    Ok(watcher)
+}
+
+#[doc = r"
+    Whether a backend error means the OS limit on watches (inotify's
+    `max_user_watches`) was reached.
+"]
+fn is_watch_limit(error: &notify::Error) -> bool {
+    match &error.kind {
+        notify::ErrorKind::MaxFilesWatch => true,
+        // ENOSPC, which inotify returns when out of watches
+        notify::ErrorKind::Io(e) => cfg!(target_os = "linux") && e.raw_os_error() == Some(28),
+        _ => false,
+    }
+}
+
+#[doc = r"
+    Describe a watch limit error, with the configured limit where it can be read.
+"]
+fn watch_limit_message(error: &notify::Error) -> String {
+    let path = error.paths.first().map(|p| format!(" while watching {}", p.display())).unwrap_or_default();
+    let limit = std::fs::read_to_string("/proc/sys/fs/inotify/max_user_watches")
+        .map(|limit| format!(" (fs.inotify.max_user_watches is {})", limit.trim()))
+        .unwrap_or_default();
+    format!("the limit on watched directories was reached{}{}", path, limit)
+}
+
+#[doc = r"
+    The error raised for a path the backend could not watch.
+"]
+fn watch_error(error: &notify::Error) -> PyErr {
+    if is_watch_limit(error) {
+        return PyRuntimeError::new_err(format!(
+            "{}; raise it, e.g. with `sudo sysctl fs.inotify.max_user_watches=524288`",
+            watch_limit_message(error)
+        ));
+    }
+    PyRuntimeError::new_err(format!("could not watch: {}", error))
+}
+
+/// How often the event loop wakes up to check whether it has been asked to stop.
+const TICK: Duration = Duration::from_millis(100);
+
+/// The minimum time between two runs of the command.
+const THROTTLE: Duration = Duration::from_secs(1);
+
+#[doc = r"
+    Run one independent session per set of options on its own thread, until all of them
+    end. The sessions share `state`, so the first one to fail stops the others and its
+    error is returned. They share `ready` and `on_lifecycle` too, which hear about the
+    group as a whole: `ready` is set and `watch-started` emitted once every session is
+    watching its paths, and `watch-stopped` once after the last session ended.
+"]
+pub(crate) fn run_many(sessions: &[Options], state: &State) -> PyResult<()> {
+    let [first, ..] = sessions else { return Ok(()) };
+    if sessions.len() == 1 {
+        return run(first, state);
+    }
+
+    // How many sessions are watching their paths
+    let armed = (Mutex::new(0), Condvar::new());
+    let (results, announced) = thread::scope(|scope| {
+        let armed = &armed;
+        let handles: Vec<_> = sessions
+            .iter()
+            .map(|options| {
+                scope.spawn(move || {
+                    let result = watch_loop(options, state, &mut || {
+                        *armed.0.lock().unwrap() += 1;
+                        armed.1.notify_all();
+                        Ok(())
+                    });
+                    if let Err(e) = &result {
+                        options.lifecycle("watch-error", Some(e.to_string()));
+                        state.stopping.store(true, Ordering::SeqCst);
+                    }
+                    result
+                })
+            })
+            .collect();
+
+        // A session that ends before arming means the group never fully starts
+        let mut count = armed.0.lock().unwrap();
+        while *count < sessions.len() && !handles.iter().any(|handle| handle.is_finished()) {
+            count = armed.1.wait_timeout(count, TICK).unwrap().0;
+        }
+        let all_armed = *count == sessions.len();
+        drop(count);
+        let announced = all_armed.then(|| {
+            let paths: Vec<String> = sessions.iter().flat_map(|options| options.paths.clone()).collect();
+            let announced = announce_started(first, &paths);
+            if let Err(e) = &announced {
+                first.lifecycle("watch-error", Some(e.to_string()));
+                state.stopping.store(true, Ordering::SeqCst);
+            }
+            announced
+        });
+        let results: Vec<PyResult<&'static str>> = handles.into_iter().map(|handle| handle.join().unwrap()).collect();
+        (results, announced)
+    });
+    state.stopping.store(false, Ordering::SeqCst);
+
+    if announced.is_some() {
+        let reasons: Vec<&str> = results.iter().map(|result| result.as_ref().map_or("error", |reason| reason)).collect();
+        let reason = ["error", "stop"].into_iter().find(|r| reasons.contains(r)).unwrap_or(reasons[0]);
+        first.lifecycle("watch-stopped", Some(reason.to_string()));
+    }
+    for result in results {
+        result?;
+    }
+    announced.unwrap_or(Ok(()))
+}
+
+#[doc = r"
+    Tell the caller a watch is armed: set `ready` and emit `watch-started` for `paths`.
+"]
+fn announce_started(options: &Options, paths: &[String]) -> PyResult<()> {
+    if let Some(ready) = &options.ready {
+        Python::with_gil(|py| ready.call_method0(py, "set"))?;
+    }
+    options.lifecycle("watch-started", Some(paths.join(", ")));
+    Ok(())
+}
+
+#[doc = r"
+    The event loop behind `watch` and `Watcher.run`, reporting its lifecycle to
+    `on_lifecycle`.
+"]
+fn run(options: &Options, state: &State) -> PyResult<()> {
+    let mut started = false;
+    let result = watch_loop(options, state, &mut || {
+        announce_started(options, &options.paths)?;
+        started = true;
+        Ok(())
+    });
+    if let Err(e) = &result {
+        options.lifecycle("watch-error", Some(e.to_string()));
+    }
+    if started {
+        let reason = match &result {
+            Err(_) => "error",
+            Ok(reason) => reason,
+        };
+        options.lifecycle("watch-stopped", Some(reason.to_string()));
+    }
+    result.map(|_| ())
+}
+
+#[doc = r"
+    Watch until the session ends, calling `armed` once every path is being watched.
+
+    Returns:
+        str: Why it ended: `stop`, `success` (with `stop_on_success`) or `disconnected`.
+
+    Raises:
+        TimeoutError: If `timeout_secs` passed first.
+"]
+fn watch_loop(
+    options: &Options,
+    state: &State,
+    armed: &mut dyn FnMut() -> PyResult<()>,
+) -> PyResult<&'static str> {
+    let mut roots = Vec::new();
+    for path in &options.paths {
+        roots.extend(options.resolve(path)?);
+    }
+    if !options.quiet {
+        println!("Watching {} for changes...", options.paths.join(", "));
+    }
+    let (tx, rx) = event_queue(options.queue_capacity, options.overflow, state.dropped_events.clone());
+
+    // Events report paths under the form the roots were registered with
+    let roots: Vec<PathBuf> = roots.iter().map(|root| to_extended_path(&absolute_root(root))).collect();
+    let watcher = match open_watcher(&tx, &roots, false) {
+        Err(e) if is_watch_limit(&e) && options.fallback_to_poll_on_limit => {
+            eprintln!("Warning: {}; falling back to polling every {:?}", watch_limit_message(&e), POLL_FALLBACK_INTERVAL);
+            let polling = open_watcher(&tx, &roots, true).map_err(|e| watch_error(&e))?;
+            *state.backend.lock().unwrap() = Some("poll");
+            options.lifecycle("backend-reopened", Some("poll".to_string()));
+            polling
+        }
+        Err(e) if is_watch_limit(&e) => {
+            return Err(PyRuntimeError::new_err(format!(
+                "{}; raise it (e.g. `sudo sysctl fs.inotify.max_user_watches=524288`) or pass fallback_to_poll_on_limit=True",
+                watch_limit_message(&e)
+            )));
+        }
+        Err(e) => return Err(watch_error(&e)),
+        Ok(watcher) => {
+            *state.backend.lock().unwrap() = Some(backend().0);
+            watcher
+        }
+    };
+    // Declared after the watcher so it is dropped first, however the loop ends
+    let _closing = Closing(&rx);
+    armed()?;
+
+    let mut session = Session::new(options, state, roots);
+    let _injecting = Injecting::new(state, tx);
+    let gives_up_at = options.timeout.map(|timeout| Instant::now() + timeout);
+
+    let mut reason = "disconnected";
+    while !state.stopping.load(Ordering::SeqCst) {
+        session.reap();
+        session.stats_if_due()?;
+        if options.stop_on_success && session.succeeded {
+            reason = "success";
+            break;
+        }
+        if gives_up_at.is_some_and(|at| Instant::now() >= at) {
+            return Err(PyTimeoutError::new_err(format!(
+                "watch timed out after {} seconds",
+                options.timeout.unwrap_or_default().as_secs_f64()
+            )));
+        }
+
+        // Pending changes run once due even if no further event arrives
+        let deadline = session.due_at();
+        let wait = deadline.map_or(TICK, |d| d.saturating_duration_since(Instant::now()).min(TICK));
+
+        match rx.recv_timeout(wait) {
+            Ok((res, injected)) => {
+                session.handle(res)?;
+                session.run_if_due();
+                session.run_idle_if_due();
+                if injected {
+                    state.mark_fed(false);
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                if deadline.is_some_and(|d| Instant::now() >= d) {
+                    session.run_if_due();
+                }
+                session.run_idle_if_due();
+                session.heartbeat_if_due();
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+
+    if state.stopping.load(Ordering::SeqCst) {
+        reason = "stop";
+    }
+    if reason == "stop" && options.drain_on_stop {
+        // Closing the queue stops new events, then whatever is already queued is processed
+        rx.close();
+        drop(watcher);
+        for res in rx.drain() {
+            session.handle(res)?;
+        }
+        session.run_pending();
+    }
+    Ok(reason)
+}
+
+#[doc = r"
+    Block until `path` exists, watching its parent directory so the file is noticed as
+    soon as it is created (or moved) into place.
+
+    Returns:
+        bool: `true` once the file exists, `false` if `timeout` passed first.
+
+    Raises:
+        ValueError: If the parent directory does not exist.
+        RuntimeError: If the parent directory cannot be watched, e.g. because it is not
+        readable or the OS limit on watches was reached.
+"]
+pub(crate) fn wait_for_file(path: &Path, timeout: Option<Duration>) -> PyResult<bool> {
+    if path.exists() {
+        return Ok(true);
+    }
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    if !parent.is_dir() {
+        return Err(PyValueError::new_err(format!(
+            "parent directory '{}' does not exist",
+            parent.display()
+        )));
+    }
+
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let (tx, rx) = std::sync::mpsc::channel();
+    let mut watcher = RecommendedWatcher::new(tx, backend().1).map_err(|e| watch_error(&e))?;
+    watcher
+        .watch(&to_extended_path(parent), RecursiveMode::NonRecursive)
+        .map_err(|e| watch_error(&e))?;
+
+    // Checked again once watching, in case the file appeared in between
+    loop {
+        if path.exists() {
+            return Ok(true);
+        }
+        let wait = match deadline {
+            Some(deadline) if Instant::now() >= deadline => return Ok(false),
+            Some(deadline) => deadline.saturating_duration_since(Instant::now()).min(TICK),
+            None => TICK,
+        };
+        // Any event (or none) is only a cue to look again
+        if let Err(RecvTimeoutError::Disconnected) = rx.recv_timeout(wait) {
+            return Ok(path.exists());
+        }
+    }
+}
+
+#[doc = r"
+    Closes the event queue when dropped. A backend blocked on a full queue would otherwise
+    never return, and some backends (FSEvents) wait for their thread when dropped, so the
+    queue has to be closed before the watcher is.
+"]
+struct Closing<'a>(&'a QueueReceiver);
+
+impl Drop for Closing<'_> {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
+
+// The `info` attribute of events passed to `feed_event`, for callbacks to tell them apart
+const INJECTED: &str = "injected";
+
+#[doc = r"
+    Makes the running session's queue available to `feed_event` until dropped, when any
+    fed events still waiting are given up on.
+"]
+struct Injecting<'a>(&'a State);
+
+impl<'a> Injecting<'a> {
+    fn new(state: &'a State, tx: QueueSender) -> Self {
+        *state.injector.lock().unwrap() = Some(tx);
+        Injecting(state)
+    }
+}
+
+impl Drop for Injecting<'_> {
+    fn drop(&mut self) {
+        // Under the injector lock, so no `feed_event` can take a ticket after this
+        let mut injector = self.0.injector.lock().unwrap();
+        *injector = None;
+        self.0.mark_fed(true);
+    }
+}
+
+#[doc = r"
+    Build the event `feed_event` injects, and `matches` checks, of the kind an
+    `event_kinds`-style selector names.
+
+    Raises:
+        ValueError: If `kind` is not a valid selector.
+"]
+fn synthetic_event(kind: &str, path: &str) -> PyResult<Event> {
+    let kind = parse_kind_selector(kind)
+        .and_then(|selector| kind_from_selector(&selector))
+        .ok_or_else(|| unknown_kind_error(kind))?;
+    Ok(Event::new(kind).add_path(PathBuf::from(path)).set_info(INJECTED))
+}
+
+#[doc = r"
+    The per-run state of the event loop: what changed since the command last ran and
+    when, and the process it last spawned.
+"]
+struct Session<'a> {
+    options: &'a Options,
+    state: &'a State,
+    roots: Vec<PathBuf>,
+    has_changes: bool,
+    // The command and the rules, each with the changes it has not run for yet
+    targets: Vec<Target>,
+    // Keep hold of the spawned processes so they can be reaped, with their run number for
+    // `wait_for_next_run`, or `None` for the `on_idle` command
+    children: Vec<(Child, Instant, Option<u64>)>,
+    last_call_time: Instant,
+    // When the latest qualifying event arrived, for the settle delay and debounce
+    last_event_time: Instant,
+    // When the oldest pending change arrived, for the debounce's max wait
+    first_pending_time: Option<Instant>,
+    // Qualifying events since the command last ran, for `min_events`
+    pending_events: u32,
+    // When `on_stats` was last called
+    last_stats: Instant,
+    // Whether a tracked command has exited successfully, for `stop_on_success`
+    succeeded: bool,
+    // Events reported so far, and when an event or heartbeat was last seen
+    reported: u64,
+    last_heartbeat: Instant,
+    // Whether `on_idle` already ran for the current quiet period
+    idle_ran: bool,
+    // Files seen so far, used to expand directory removals
+    known: BTreeSet<PathBuf>,
+    // When recently created paths appeared, for coalescing their writes
+    created: HashMap<String, Instant>,
+    // The last mode seen for each path, for reporting permission changes
+    modes: HashMap<PathBuf, u32>,
+    // The absolute `mirror_to` directory
+    mirror: Option<PathBuf>,
+}
+
+impl<'a> Session<'a> {
+    fn new(options: &'a Options, state: &'a State, roots: Vec<PathBuf>) -> Self {
+        Session {
+            options,
+            state,
+            roots,
+            has_changes: false,
+            targets: Target::from_options(options),
+            children: Vec::new(),
+            // Initialize to 1 second ago
+            last_call_time: Instant::now() - THROTTLE,
+            last_event_time: Instant::now(),
+            first_pending_time: None,
+            pending_events: 0,
+            last_stats: Instant::now(),
+            succeeded: false,
+            reported: 0,
+            last_heartbeat: Instant::now(),
+            idle_ran: false,
+            known: BTreeSet::new(),
+            created: HashMap::new(),
+            modes: HashMap::new(),
+            mirror: options.mirror_to.as_ref().map(|m| std::path::absolute(m).unwrap_or_else(|_| m.clone())),
+        }
+    }
+
+    #[doc = r"
+        When the pending changes are due to run the command: after the throttle since the
+        last run, or the debounce since the last event when one is set, pushed back by
+        any settle delay. `max_wait` caps how long the first pending change can wait.
+        Never, until `min_events` qualifying events have accumulated.
+    "]
+    fn due_at(&self) -> Option<Instant> {
+        let first_pending = self.first_pending_time?;
+        if self.pending_events < self.options.min_events {
+            return None;
+        }
+
+        let mut at = match self.options.debounce {
+            Some(debounce) => self.last_event_time + debounce,
+            None => self.last_call_time + THROTTLE,
+        };
+        if let Some(settle) = self.options.settle {
+            at = at.max(self.last_event_time + settle);
+        }
+        if let (Some(max_wait), Some(_)) = (self.options.max_wait, self.options.debounce) {
+            at = at.min(first_pending + max_wait);
+        }
+        Some(at)
+    }
+
+    #[doc = r"
+        Filter a notify result and record the changes that pass.
+    "]
+    fn handle(&mut self, res: notify::Result<Event>) -> PyResult<()> {
+        self.last_heartbeat = Instant::now();
+        let event = match res {
+            Ok(event) => event,
+            Err(error) => {
+                eprintln!("Error: {:?}", error);
+                self.options.lifecycle("watch-error", Some(format!("{:?}", error)));
+                return Ok(());
+            }
+        };
+
+        let events = if self.options.expand_dir_events {
+            expand_dir_event(event, &mut self.known)
+        } else {
+            vec![event]
+        };
+
+        for event in &events {
+            // Track modes before filtering, so a permission change knows the previous mode
+            let modes = self.track_mode(event);
+            let verdict = match self.state.muted_by(event) {
+                Some(pattern) => Err(format!("muted by {}", pattern)),
+                None => check_filters(&self.state.filters.lock().unwrap(), &self.roots, event),
+            };
+            let verdict = verdict.and_then(|_| self.coalesce(event));
+            {
+                let mut counters = self.state.counters.lock().unwrap();
+                counters.events_seen += 1;
+                counters.events_filtered += u64::from(verdict.is_err());
+            }
+            if let Err(reason) = verdict {
+                if self.options.debug_filters {
+                    println!("skipped {} ({})", event_path(event), reason);
+                }
+                continue;
+            }
+            self.report(event, modes)?;
+        }
+        Ok(())
+    }
+
+    #[doc = r"
+        Record a change that passed the filters and hand it to the callbacks, along with
+        the file's mode before and after it for a tracked metadata change.
+    "]
+    fn report(&mut self, event: &Event, modes: Option<(Option<u32>, Option<u32>)>) -> PyResult<()> {
+        self.reported += 1;
+        // Convert the event to a tuple
+        let mut change = event_to_tuple(event);
+        let path = event.paths.first().map(PathBuf::as_path).unwrap_or(Path::new(""));
+        let relative = relative_to_roots(path, &self.roots);
+        let paths: Vec<String> = if self.options.relative_paths {
+            event.paths.iter().map(|p| self.reported_path(p)).collect()
+        } else {
+            event.paths.iter().map(|p| display_path(p)).collect()
+        };
+        if self.options.relative_paths {
+            change.1 = paths.first().cloned().unwrap_or(change.1);
+        }
+        let record = Change::new(change.0.clone(), change.1.clone());
+        let key = dedup_key(&change.1);
+        if let (Some(mirror), Some(relative)) = (&self.mirror, &relative) {
+            self.mirror_change(mirror, path, relative, &event.kind);
+        }
+        let mut queued = false;
+        let mut matched_rule = false;
+        // A pathless event (e.g. a rescan) only goes to the callbacks; commands run for paths
+        let targets = if event.paths.is_empty() { &mut [][..] } else { &mut self.targets[..] };
+        for target in targets {
+            let accepts = match &target.pattern {
+                None => true,
+                Some(_) if matched_rule && self.options.first_match => false,
+                Some(pattern) => {
+                    let matches = pattern.matches(&change.1)
+                        || relative.as_ref().is_some_and(|r| pattern.matches_path(r));
+                    matched_rule |= matches;
+                    matches
+                }
+            };
+            if matches!(event.kind, EventKind::Remove(_)) {
+                target.unchanged.retain(|paths, _| !paths.contains(&key));
+            }
+            if accepts {
+                // Keep one entry per path, reflecting its latest change
+                target.pending.retain(|pending| dedup_key(&pending.path) != key);
+                target.pending.push(record.clone());
+                queued = true;
+            }
+        }
+        self.last_event_time = Instant::now();
+        self.idle_ran = false;
+        if queued {
+            self.pending_events = self.pending_events.saturating_add(1);
+        }
+        if queued && self.first_pending_time.is_none() {
+            self.first_pending_time = Some(self.last_event_time);
+        }
+
+        if !self.has_changes {
+            self.has_changes = true;
+            if !self.options.quiet {
+                println!("Change detected!");
+                println!("Change: {:?}", change);
+            }
+        }
+
+        if self.state.callbacks.lock().unwrap().is_empty() {
+            return Ok(());
+        }
+        Python::with_gil(|py| {
+            // Call a snapshot so callbacks can add or remove callbacks themselves
+            let callbacks = self.state.callbacks.lock().unwrap().clone();
+            let attrs = if self.options.include_attrs {
+                let attrs = event_attrs(py, event)?;
+                if let Some((before, after)) = modes {
+                    attrs.set_item("mode_before", before)?;
+                    attrs.set_item("mode_after", after)?;
+                }
+                Some(attrs)
+            } else {
+                None
+            };
+            let payload = event_payload(py, self.options.event_format, paths, &record, attrs)?;
+
+            for callback in callbacks {
+                if let Err(e) = callback.call1(py, (payload.clone_ref(py),)) {
+                    e.print(py);
+                }
+            }
+            Ok(())
+        })
+    }
+
+    #[doc = r"
+        A path as reported with `relative_paths`: relative to the longest watched root
+        containing it, or as is when it is not below one (or is a root itself).
+    "]
+    fn reported_path(&self, path: &Path) -> String {
+        match relative_to_roots(path, &self.roots) {
+            Some(relative) if !relative.as_os_str().is_empty() => display_path(&relative),
+            _ => display_path(path),
+        }
+    }
+
+    #[doc = r"
+        Apply a change to the `mirror_to` directory. Access events are ignored, as is
+        anything inside the mirror, which would otherwise mirror itself forever.
+    "]
+    fn mirror_change(&self, mirror: &Path, path: &Path, relative: &Path, kind: &EventKind) {
+        let inside_mirror = std::path::absolute(path).is_ok_and(|p| p.starts_with(mirror));
+        if matches!(kind, EventKind::Access(_)) || inside_mirror {
+            return;
+        }
+
+        // A watched file is its own root, so it is mirrored under its name
+        let target = match path.file_name() {
+            Some(name) if relative.as_os_str().is_empty() && !path.is_dir() => mirror.join(name),
+            _ => mirror.join(relative),
+        };
+        if let Err(error) = sync_mirror(path, &target) {
+            let message = format!("{:?} mirroring {} to {}", error, path.display(), target.display());
+            eprintln!("Error: {}", message);
+            self.options.lifecycle("watch-error", Some(message));
+        }
+    }
+
+    #[doc = r"
+        When modes are tracked, record the current mode of the event's path.
+
+        Returns:
+            Optional[Tuple[Optional[int], Optional[int]]]: For a metadata event, the mode
+            before and after it.
+    "]
+    fn track_mode(&mut self, event: &Event) -> Option<(Option<u32>, Option<u32>)> {
+        if !self.state.filters.lock().unwrap().tracks_modes() {
+            return None;
+        }
+        let path = event.paths.first()?;
+
+        let after = file_mode(path);
+        let before = match after {
+            Some(mode) => self.modes.insert(path.clone(), mode),
+            None => self.modes.remove(path),
+        };
+        matches!(event.kind, EventKind::Modify(ModifyKind::Metadata(_))).then_some((before, after))
+    }
+
+    #[doc = r"
+        With `coalesce_writes`, fold a `Modify` into a `Create` of the same path that
+        happened within the coalesce window.
+
+        Returns:
+            Result[None, str]: `Err` with the reason if the event was folded away.
+    "]
+    fn coalesce(&mut self, event: &Event) -> Result<(), String> {
+        if !self.options.coalesce_writes {
+            return Ok(());
+        }
+        let Some(path) = event.paths.first() else { return Ok(()) };
+        let key = dedup_key(&display_path(path));
+
+        let now = Instant::now();
+        let window = self.options.coalesce_window;
+        self.created.retain(|_, at| now.duration_since(*at) < window);
+
+        match event.kind {
+            EventKind::Create(_) => {
+                self.created.insert(key, now);
+            }
+            EventKind::Modify(_) if self.created.contains_key(&key) => {
+                return Err("coalesced into Create".to_string());
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    #[doc = r"
+        Run the command if there are pending changes and they are due (see `due_at`).
+    "]
+    fn run_if_due(&mut self) {
+        if self.due_at().is_some_and(|at| Instant::now() >= at) {
+            self.run_pending();
+        }
+    }
+
+    #[doc = r"
+        Call `on_stats` with the current counters every `stats_interval_secs`.
+    "]
+    fn stats_if_due(&mut self) -> PyResult<()> {
+        let (Some(callback), Some(interval)) = (&self.options.on_stats, self.options.stats_interval) else {
+            return Ok(());
+        };
+        if self.last_stats.elapsed() < interval {
+            return Ok(());
+        }
+        self.last_stats = Instant::now();
+        Python::with_gil(|py| {
+            if let Err(e) = callback.call1(py, (self.state.stats(py)?,)) {
+                e.print(py);
+            }
+            Ok(())
+        })
+    }
+
+    #[doc = r"
+        Print a heartbeat line if `heartbeat_secs` passed without any event arriving
+        (or since the previous heartbeat), so a quiet watch visibly stays alive.
+    "]
+    fn heartbeat_if_due(&mut self) {
+        let Some(interval) = self.options.heartbeat else {
+            return;
+        };
+        if self.options.quiet || self.last_heartbeat.elapsed() < interval {
+            return;
+        }
+        self.last_heartbeat = Instant::now();
+        println!("still watching {} ({} events so far)", self.options.paths.join(", "), self.reported);
+    }
+
+    #[doc = r"
+        Run the `on_idle` command if no qualifying event has arrived for `idle_after`
+        (counting from the start of the session), once per quiet period.
+    "]
+    fn run_idle_if_due(&mut self) {
+        let Some(command) = &self.options.on_idle else {
+            return;
+        };
+        if self.idle_ran || self.last_event_time.elapsed() < self.options.idle_after {
+            return;
+        }
+        self.idle_ran = true;
+        self.reap();
+
+        if !self.options.quiet {
+            println!("Running idle command: {:?}", command);
+        }
+        let spawned = call_command(command.clone(), &[], None, &[], &self.options.spawn);
+        self.track(command, spawned, true);
+    }
+
+    #[doc = r"
+        Let go of the commands that already exited, noting whether any succeeded. The
+        `on_idle` command is counted but does not end the watch or wake `wait_for_next_run`.
+    "]
+    fn reap(&mut self) {
+        let mut succeeded = false;
+        let mut counters = self.state.counters.lock().unwrap();
+        self.children.retain_mut(|(child, started, run)| match child.try_wait() {
+            Ok(None) => true,
+            Ok(Some(status)) => {
+                if let Some(run) = *run {
+                    self.state.mark_finished(run, status.code());
+                    succeeded |= status.success();
+                }
+                counters.command_failures += u64::from(!status.success());
+                counters.commands_finished += 1;
+                counters.command_time += started.elapsed();
+                false
+            }
+            Err(_) => false,
+        });
+        self.succeeded |= succeeded;
+    }
+
+    #[doc = r"
+        Record a spawned command as the latest one and keep its handle, or let go of it
+        when commands are detached. A command that could not be started is reported and
+        counted as a failure, and the watch carries on. `idle` marks the `on_idle` command.
+
+        Returns:
+            bool: Whether the command started.
+    "]
+    fn track(&mut self, command: &str, spawned: io::Result<Child>, idle: bool) -> bool {
+        let child = match spawned {
+            Ok(child) => child,
+            Err(error) => {
+                eprintln!("Error: could not run {:?}: {}", command, error);
+                self.options.lifecycle("watch-error", Some(format!("could not run {:?}: {}", command, error)));
+                self.state.counters.lock().unwrap().command_failures += 1;
+                return false;
+            }
+        };
+        *self.state.last_pid.lock().unwrap() = Some(child.id());
+        let run = {
+            let mut counters = self.state.counters.lock().unwrap();
+            counters.commands_run += 1;
+            counters.commands_run
+        };
+        if self.options.spawn.detach {
+            release(child);
+        } else {
+            self.children.push((child, Instant::now(), (!idle).then_some(run)));
+        }
+        true
+    }
+
+    #[doc = r"
+        Run the command for the pending changes straight away, if there are any.
+    "]
+    fn run_pending(&mut self) {
+        if self.first_pending_time.is_none() {
+            return;
+        }
+        // Reap the previous processes that already exited
+        self.reap();
+
+        let mut spawned = Vec::new();
+        // The contents each target runs for, recorded once its command actually started
+        let mut contents = Vec::new();
+        for (i, target) in self.targets.iter_mut().enumerate() {
+            if target.pending.is_empty() {
+                continue;
+            }
+
+            if self.options.skip_unchanged {
+                let mut paths: Vec<String> = target.pending.iter().map(|c| dedup_key(&c.path)).collect();
+                paths.sort();
+                let hash = contents_hash(&target.pending, &self.roots, self.options.relative_paths);
+                if target.unchanged.get(&paths) == Some(&hash) {
+                    if !self.options.quiet {
+                        println!("Skipping command, contents unchanged: {:?}", target.command);
+                    }
+                    target.pending.clear();
+                    continue;
+                }
+                contents.push((i, paths, hash));
+            }
+
+            if !self.options.quiet {
+                println!("Running command: {:?}", target.command);
+            }
+            let batches = if self.options.paths_as_args {
+                let base_len = substitute_placeholders(&target.command, &target.pending).len();
+                arg_batches(base_len, &target.pending)
+            } else {
+                vec![&target.pending[..]]
+            };
+            for batch in batches {
+                let paths: Vec<&str> = batch.iter().map(|c| c.path.as_str()).collect();
+                let input = self.options.paths_to_stdin.then(|| paths.join("\n") + "\n");
+                let args = if self.options.paths_as_args { &paths[..] } else { &[] };
+                let spawn = call_command(target.command.clone(), args, input, batch, &self.options.spawn);
+                spawned.push((i, target.command.clone(), spawn));
+            }
+            target.pending.clear();
+        }
+        let mut failed = Vec::new();
+        for (i, command, child) in spawned {
+            if !self.track(&command, child, false) {
+                failed.push(i);
+            }
+        }
+        for (i, paths, hash) in contents {
+            if !failed.contains(&i) {
+                self.targets[i].unchanged.insert(paths, hash);
+            }
+        }
+        self.first_pending_time = None;
+        self.pending_events = 0;
+        // Update the last call time
+        self.last_call_time = Instant::now();
+    }
+}
+
+#[doc = r"
+    A command the session runs: the main `command`, or a rule's command together with
+    the pattern a change must match to trigger it.
+"]
+struct Target {
+    command: String,
+    pattern: Option<Pattern>,
+    // Changes the command has not run for yet
+    pending: Vec<Change>,
+    // The contents hash of each set of files the command last ran for, for `skip_unchanged`
+    unchanged: HashMap<Vec<String>, u64>,
+}
+
+impl Target {
+    fn from_options(options: &Options) -> Vec<Target> {
+        let command = options.command.iter().map(|command| Target {
+            command: command.clone(),
+            pattern: None,
+            pending: Vec::new(),
+            unchanged: HashMap::new(),
+        });
+        let rules = options.rules.iter().map(|(pattern, command)| Target {
+            command: command.clone(),
+            pattern: Some(pattern.clone()),
+            pending: Vec::new(),
+            unchanged: HashMap::new(),
+        });
+        command.chain(rules).collect()
+    }
+}
+
+#[doc = r"
+    Hash the current contents of the changed files together, for `skip_unchanged`. A
+    path that cannot be read, such as a removed file or a directory, counts as absent.
+    Paths reported relative with `relative_paths` are looked up under the roots.
+"]
+fn contents_hash(changes: &[Change], roots: &[PathBuf], relative_paths: bool) -> u64 {
+    let mut paths: Vec<&str> = changes.iter().map(|c| c.path.as_str()).collect();
+    paths.sort_by_key(|path| dedup_key(path));
+    let mut hasher = DefaultHasher::new();
+    for path in paths {
+        let path = Path::new(path);
+        let source = match roots.iter().map(|root| root.join(path)).find(|p| p.exists()) {
+            Some(joined) if relative_paths && path.is_relative() => joined,
+            _ => path.to_path_buf(),
+        };
+        dedup_key(path.to_string_lossy().as_ref()).hash(&mut hasher);
+        std::fs::read(source).ok().hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+#[doc = r"
+    Whether a change of `kind` to `path` would pass the filters, without watching
+    anything; the decision `matches` exposes.
+
+    Raises:
+        ValueError: If `kind` or a filter option is invalid.
+"]
+pub(crate) fn would_match(path: &str, kind: &str, options: &[(&str, &PyAny)]) -> PyResult<bool> {
+    let mut filters = Filters::default();
+    for (key, value) in options {
+        filters.set(key, value)?;
+    }
+    let event = synthetic_event(kind, path)?;
+    Ok(check_filters(&filters, &[], &event).is_ok())
+}
+
+#[doc = r"
+    Check an event against the configured filters.
+
+    Returns:
+        Result[None, str]: `Ok` if the event should be reported, otherwise the reason it
+        was skipped, naming the filter responsible.
+"]
+fn check_filters(filters: &Filters, roots: &[PathBuf], event: &Event) -> Result<(), String> {
+    if filters.ignore_root_events && !event.paths.is_empty() && event.paths.iter().all(|p| roots.contains(p)) {
+        return Err("path is a watched root".to_string());
+    }
+
+    if let Some(selectors) = &filters.event_kinds {
+        if !selectors.iter().any(|selector| kind_matches(selector, &event.kind)) {
+            return Err(format!("kind {} not selected", kind_path(&event.kind).join("::")));
+        }
+    }
+
+    // Globs match the path as reported or relative to its root, like rules
+    let relative = event.paths.first().and_then(|p| relative_to_roots(p, roots));
+    let matching = |patterns: &[Pattern]| {
+        patterns
+            .iter()
+            .find(|pattern| {
+                event.paths.iter().any(|p| pattern.matches_path(p))
+                    || relative.as_ref().is_some_and(|r| pattern.matches_path(r))
+            })
+            .map(|pattern| pattern.as_str().to_string())
+    };
+    if let Some(filter) = &filters.filter {
+        if !filter.matches(event, relative.as_deref()) {
+            return Err(format!("rejected by {}", filter));
+        }
+    }
+
+    if let Some(pattern) = matching(&filters.ignore) {
+        return Err(format!("ignored by {}", pattern));
+    }
+    if let Some(include) = &filters.include {
+        if matching(include).is_none() {
+            return Err("not matched by include".to_string());
+        }
+    }
+
+    // If extensions are provided, filter out events that don't match
+    if let Some(exts) = &filters.extensions {
+        let matches = event.paths.iter().any(|p| {
+            p.extension()
+                .map(|ext| exts.contains(&ext.to_string_lossy().into_owned()))
+                .unwrap_or(false)
+        });
+
+        if !matches {
+            return Err(match event.paths.first().and_then(|p| p.extension()) {
+                Some(ext) => format!("extension {} not in set", ext.to_string_lossy()),
+                None => "no extension".to_string(),
+            });
+        }
+    }
+
+    if let Some(names) = &filters.filenames {
+        let matches = event.paths.iter().any(|p| {
+            p.file_name()
+                .map(|name| names.iter().any(|n| name == n.as_str()))
+                .unwrap_or(false)
+        });
+
+        if !matches {
+            let name = event.paths.first().and_then(|p| p.file_name()).map(|n| n.to_string_lossy());
+            return Err(format!("file name {} not in set", name.unwrap_or_default()));
+        }
+    }
+    Ok(())
+}
+
+#[doc = r"
+    The permission bits of a file, where the platform has them.
+"]
+#[cfg(unix)]
+fn file_mode(path: &Path) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::metadata(path).ok().map(|m| m.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+fn file_mode(_path: &Path) -> Option<u32> {
+    None
+}
+
+#[doc = r"
+    The first path of an event, for log messages.
+"]
+fn event_path(event: &Event) -> String {
+    event.paths.first().map(|p| display_path(p)).unwrap_or_default()
+}
+
+#[doc = r"
+    Replace a directory `Create`/`Remove` with one synthetic event per file it contained,
+    keeping `known` up to date with the files seen so far, renames included. Other events,
+    and the creation of an empty directory, pass through.
+
+    Returns:
+        List[Event]: The events to report in place of `event`.
+"]
+fn expand_dir_event(event: Event, known: &mut BTreeSet<PathBuf>) -> Vec<Event> {
+    let Some(path) = event.paths.first().cloned() else { return vec![event] };
+
+    match event.kind {
+        EventKind::Create(CreateKind::Folder) | EventKind::Create(CreateKind::Any) if path.is_dir() => {
+            let files = walk_files(&path);
+            if files.is_empty() {
+                return vec![event];
+            }
+            files
+                .into_iter()
+                .map(|file| {
+                    known.insert(file.clone());
+                    Event::new(EventKind::Create(CreateKind::File)).add_path(file)
+                })
+                .collect()
+        }
+        EventKind::Remove(_) => {
+            known.remove(&path);
+            let children = known_below(known, &path);
+            if children.is_empty() {
+                return vec![event];
+            }
+
+            children
+                .into_iter()
+                .map(|file| {
+                    known.remove(&file);
+                    Event::new(EventKind::Remove(RemoveKind::File)).add_path(file)
+                })
+                .collect()
+        }
+        // The old name is gone, along with anything seen below it; the new one shows up in
+        // a later event, or as the second path here
+        EventKind::Modify(ModifyKind::Name(mode)) => {
+            for old in known_below(known, &path).into_iter().chain([path]) {
+                known.remove(&old);
+            }
+            if let (RenameMode::Both, Some(new)) = (mode, event.paths.get(1)) {
+                known.insert(new.clone());
+            }
+            vec![event]
+        }
+        EventKind::Create(_) | EventKind::Modify(_) => {
+            known.insert(path);
+            vec![event]
+        }
+        _ => vec![event],
+    }
+}
+
+// The known paths strictly below `dir`
+fn known_below(known: &BTreeSet<PathBuf>, dir: &Path) -> Vec<PathBuf> {
+    known
+        .range(dir.to_path_buf()..)
+        .filter(|p| p.as_path() != dir)
+        .take_while(|p| p.starts_with(dir))
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify::EventHandler;
+
+    // A watch of a fresh directory that runs no command, started on a thread
+    fn start(name: &str, configure: impl FnOnce(&mut Options)) -> (Arc<State>, PathBuf, thread::JoinHandle<()>) {
+        let root = std::env::temp_dir().join(format!("xpectate-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let mut options = Options {
+            paths: vec![root.display().to_string()],
+            quiet: true,
+            ..Default::default()
+        };
+        configure(&mut options);
+        let state = Arc::new(State::for_options(&options));
+        let session = state.clone();
+        let handle = thread::spawn(move || run(&options, &session).unwrap());
+        while state.injector.lock().unwrap().is_none() {
+            thread::sleep(Duration::from_millis(5));
+        }
+        (state, root, handle)
+    }
+
+    fn fed(kind: &str, path: &Path) -> Event {
+        synthetic_event(kind, &path.display().to_string()).unwrap()
+    }
+
+    fn dir_event(kind: EventKind, paths: &[&Path]) -> Event {
+        paths.iter().fold(Event::new(kind), |event, path| event.add_path(path.to_path_buf()))
+    }
+
+    #[test]
+    fn empty_directory_creation_is_reported() {
+        let root = std::env::temp_dir().join(format!("xpectate-empty-dir-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let mut known = BTreeSet::new();
+        let events = expand_dir_event(dir_event(EventKind::Create(CreateKind::Folder), &[&root]), &mut known);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind, EventKind::Create(CreateKind::Folder));
+        assert_eq!(events[0].paths.as_slice(), std::slice::from_ref(&root));
+        assert!(known.is_empty());
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn renames_update_the_known_files() {
+        let mut known: BTreeSet<PathBuf> = ["/src/a.rs", "/src/lib/b.rs", "/srcs/c.rs"].map(PathBuf::from).into();
+        let rename = ModifyKind::Name(RenameMode::Both);
+        expand_dir_event(dir_event(EventKind::Modify(rename), &[Path::new("/src"), Path::new("/dst")]), &mut known);
+        assert_eq!(known, ["/dst", "/srcs/c.rs"].map(PathBuf::from).into());
+
+        let moved_away = ModifyKind::Name(RenameMode::From);
+        expand_dir_event(dir_event(EventKind::Modify(moved_away), &[Path::new("/srcs/c.rs")]), &mut known);
+        assert_eq!(known, ["/dst"].map(PathBuf::from).into());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn changes_inside_the_throttle_still_run() {
+        // `touch` creates the files it is given, so each path shows up once a run got it
+        let (state, root, handle) = start("throttle", |options| {
+            options.command = Some("touch".to_string());
+            options.paths_as_args = true;
+            options.spawn.exec = true;
+        });
+        let (first, second) = (root.join("first.txt"), root.join("second.txt"));
+        state.feed(fed("Modify", &first)).unwrap();
+        thread::sleep(Duration::from_millis(200));
+        state.feed(fed("Modify", &second)).unwrap();
+
+        let gives_up_at = Instant::now() + Duration::from_secs(5);
+        while !(first.exists() && second.exists()) && Instant::now() < gives_up_at {
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert!(first.exists() && second.exists());
+
+        state.stopping.store(true, Ordering::SeqCst);
+        handle.join().unwrap();
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn pathless_events_do_not_run_commands() {
+        let (state, root, handle) = start("pathless", |options| {
+            options.command = Some("true".to_string());
+            options.spawn.exec = true;
+        });
+        state.feed(Event::new(EventKind::Other)).unwrap();
+        assert_eq!(events_seen(&state), 1);
+        assert_eq!(state.counters.lock().unwrap().commands_run, 0);
+        state.feed(fed("Modify", &root.join("a.txt"))).unwrap();
+        assert_eq!(state.counters.lock().unwrap().commands_run, 1);
+
+        state.stopping.store(true, Ordering::SeqCst);
+        handle.join().unwrap();
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn the_idle_command_does_not_count_as_a_run() {
+        let (state, root, handle) = start("idle", |options| {
+            options.on_idle = Some("true".to_string());
+            options.idle_after = Duration::from_millis(10);
+            options.spawn.exec = true;
+            options.stop_on_success = true;
+        });
+        let gives_up_at = Instant::now() + Duration::from_secs(5);
+        while state.counters.lock().unwrap().commands_finished == 0 && Instant::now() < gives_up_at {
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(state.counters.lock().unwrap().commands_finished, 1);
+        // Past the next reap, the watch is still going and no run has finished
+        thread::sleep(Duration::from_millis(250));
+        assert!(!handle.is_finished());
+        assert!(state.runs.lock().unwrap().finished.is_none());
+
+        state.stopping.store(true, Ordering::SeqCst);
+        handle.join().unwrap();
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn commands_that_fail_to_start_are_not_skipped_as_unchanged() {
+        let (state, root, handle) = start("unchanged", |options| {
+            options.command = Some("xpectate-no-such-program".to_string());
+            options.spawn.exec = true;
+            options.skip_unchanged = true;
+        });
+        let path = root.join("a.txt");
+        std::fs::write(&path, "same").unwrap();
+        state.feed(fed("Modify", &path)).unwrap();
+        state.feed(fed("Modify", &path)).unwrap();
+
+        let gives_up_at = Instant::now() + Duration::from_secs(5);
+        while state.counters.lock().unwrap().command_failures < 2 && Instant::now() < gives_up_at {
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(state.counters.lock().unwrap().command_failures, 2);
+
+        state.stopping.store(true, Ordering::SeqCst);
+        handle.join().unwrap();
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn a_watched_file_is_mirrored_under_its_name() {
+        let mirror = std::env::temp_dir().join(format!("xpectate-mirror-copy-{}", std::process::id()));
+        let (state, root, handle) = start("mirror", |options| {
+            let file = PathBuf::from(&options.paths[0]).join("a.txt");
+            std::fs::write(&file, "contents").unwrap();
+            options.paths = vec![file.display().to_string()];
+            options.mirror_to = Some(mirror.clone());
+        });
+        let file = root.join("a.txt");
+        state.feed(fed("Modify", &to_extended_path(&absolute_root(&file)))).unwrap();
+        assert_eq!(std::fs::read_to_string(mirror.join("a.txt")).unwrap(), "contents");
+
+        state.stopping.store(true, Ordering::SeqCst);
+        handle.join().unwrap();
+        std::fs::remove_dir_all(root).unwrap();
+        std::fs::remove_dir_all(mirror).unwrap();
+    }
+
+    fn events_seen(state: &State) -> u64 {
+        state.counters.lock().unwrap().events_seen
+    }
+
+    #[test]
+    fn fed_events_are_handled_before_feed_returns() {
+        let (state, root, handle) = start("feed", |_| {});
+        state.feed(fed("Modify", &root.join("a.txt"))).unwrap();
+        assert_eq!(events_seen(&state), 1);
+        state.feed(fed("Create", &root.join("b.txt"))).unwrap();
+        assert_eq!(events_seen(&state), 2);
+
+        state.stopping.store(true, Ordering::SeqCst);
+        handle.join().unwrap();
+        assert!(state.feed(fed("Modify", &root.join("a.txt"))).is_err());
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn fed_events_pass_through_the_filters() {
+        let (state, root, handle) = start("feed-filters", |options| {
+            options.filters.extensions = Some(vec!["rs".to_string()]);
+        });
+        state.feed(fed("Modify", &root.join("a.txt"))).unwrap();
+        state.feed(fed("Modify", &root.join("a.rs"))).unwrap();
+        let counters = state.counters.lock().unwrap();
+        assert_eq!((counters.events_seen, counters.events_filtered), (2, 1));
+        drop(counters);
+
+        state.stopping.store(true, Ordering::SeqCst);
+        handle.join().unwrap();
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn feeding_a_full_queue_does_not_hang() {
+        for overflow in [Overflow::DropNewest, Overflow::DropOldest] {
+            let (state, root, handle) = start("feed-full", |options| {
+                options.queue_capacity = Some(1);
+                options.overflow = overflow;
+            });
+            for i in 0..20 {
+                state.feed(fed("Modify", &root.join(format!("{}.txt", i)))).unwrap();
+            }
+            assert_eq!(events_seen(&state), 20);
+
+            state.stopping.store(true, Ordering::SeqCst);
+            handle.join().unwrap();
+            std::fs::remove_dir_all(root).unwrap();
+        }
+    }
+
+    #[test]
+    fn a_blocked_backend_does_not_keep_the_session_running() {
+        for drain_on_stop in [false, true] {
+            let (state, root, handle) = start("blocked", |options| {
+                options.queue_capacity = Some(1);
+                options.overflow = Overflow::Block;
+                options.drain_on_stop = drain_on_stop;
+            });
+            // Stands in for a backend thread that keeps reporting events
+            let mut tx = state.injector.lock().unwrap().clone().unwrap();
+            let ended = Arc::new(AtomicBool::new(false));
+            let backend = {
+                let ended = ended.clone();
+                let path = root.join("a.txt");
+                thread::spawn(move || {
+                    while !ended.load(Ordering::SeqCst) {
+                        tx.handle_event(Ok(fed("Modify", &path)));
+                    }
+                })
+            };
+            thread::sleep(Duration::from_millis(50));
+            state.stopping.store(true, Ordering::SeqCst);
+            handle.join().unwrap();
+            ended.store(true, Ordering::SeqCst);
+            backend.join().unwrap();
+            std::fs::remove_dir_all(root).unwrap();
+        }
+    }
+
+    #[test]
+    fn feeding_while_the_session_ends_does_not_hang() {
+        let (state, root, handle) = start("feed-stop", |_| {});
+        let feeder = {
+            let state = state.clone();
+            let path = root.join("a.txt");
+            // Every call returns, whether or not the session handled its event
+            thread::spawn(move || while state.feed(fed("Modify", &path)).is_ok() {})
+        };
+        thread::sleep(Duration::from_millis(50));
+        state.stopping.store(true, Ordering::SeqCst);
+        handle.join().unwrap();
+        feeder.join().unwrap();
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}